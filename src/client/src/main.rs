@@ -1,3 +1,6 @@
+//the connection is used once joining a game is implemented
+#[allow(dead_code)]
+mod net;
 #[allow(unused_imports)]
 mod ui;
 use core::{AllBuildings, Game, Player};
@@ -75,8 +78,8 @@ fn main() -> Result<(), io::Error> {
                 //we need to free resources to update the screen
                 Message::NextIteration => (),
                 Message::Input(key) => match key {
-                    Up => println!("HEy!"),
-                    Down => todo!(),
+                    KeyCode::Up => println!("HEy!"),
+                    KeyCode::Down => todo!(),
                     _ => println!("Nope"),
                 },
                 _ => unreachable!(),
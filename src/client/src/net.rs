@@ -0,0 +1,269 @@
+//! The link between the client and its server, see `core::net`.
+//!
+//! The client says hello as soon as it connects, and speaks the codec and compression the server agreed on from then on.
+//! Once logged in, it fetches the definitions of the server unless it already has them in its cache.
+use core::net::{Channel, MAX_DATAGRAM};
+use core::response::{Capability, Codec, Hello, Json};
+use core::{Definitions, Event, Pack, Response};
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+//what the client asks the server for when saying hello
+const CAPABILITIES: &[Capability] = &[Capability::Compression, Capability::Binary];
+//time waited between two looks at the socket during the handshake
+const HANDSHAKE_POLL: Duration = Duration::from_millis(10);
+
+pub struct Connection {
+    socket: UdpSocket,
+    channel: Channel,
+    buf: Vec<u8>,
+    //the reply of the server to the hello of the client
+    hello: Hello,
+    //where the definitions of the servers are kept between games
+    cache: PathBuf,
+    definitions: Option<Definitions>,
+}
+
+impl Connection {
+    /// Opens a link with the server at `addr` and says hello, waiting for the server to reply.
+    /// The definitions sent by the server are cached in the `cache` directory.
+    ///
+    /// # Errors
+    /// Besides network errors, this fails if the server doesn't reply in time or refuses the client,
+    /// like when it speaks another version of the protocol.
+    pub fn connect<A: ToSocketAddrs, P: Into<PathBuf>>(
+        addr: A,
+        cache: P,
+    ) -> io::Result<Connection> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        //the client polls the connection between two frames, it mustn't wait for the server
+        socket.set_nonblocking(true)?;
+        let mut channel = Channel::new();
+        let greeting = Response::Hello(Hello::new(CAPABILITIES.to_vec()));
+        channel
+            .send(&Json.encode(&greeting).map_err(invalid)?, Instant::now())
+            .map_err(invalid)?;
+        let mut buf = vec![0; MAX_DATAGRAM];
+        //the reply of the server is the first message it sends, in JSON
+        let hello = loop {
+            flush(&socket, &mut channel)?;
+            if let Some(message) = receive(&socket, &mut channel, &mut buf)?.into_iter().next() {
+                match Json.decode(&message).map_err(invalid)? {
+                    Response::Hello(hello) => break hello,
+                    other => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::ConnectionRefused,
+                            format!("the server refused the client: {:?}", other),
+                        ))
+                    }
+                }
+            }
+            thread::sleep(HANDSHAKE_POLL);
+        };
+        Ok(Connection {
+            socket,
+            channel,
+            buf,
+            hello,
+            cache: cache.into(),
+            definitions: None,
+        })
+    }
+
+    /// The buildings and resources of the game, once they are known.
+    pub fn definitions(&self) -> Option<&Definitions> {
+        self.definitions.as_ref()
+    }
+
+    /// Sends `response` to the server. It is delivered even if datagrams are lost, as long as `poll` is called.
+    pub fn send(&mut self, response: &Response) -> io::Result<()> {
+        let message = self.hello.encode(response).map_err(invalid)?;
+        self.channel
+            .send(&message, Instant::now())
+            .map_err(invalid)?;
+        flush(&self.socket, &mut self.channel)
+    }
+
+    /// The responses of the server received since the last call, in the order they were sent.
+    ///
+    /// This also sends again what the server didn't acknowledge in time.
+    /// The responses about the definitions of the game are handled by the connection, see `definitions`.
+    pub fn poll(&mut self) -> io::Result<Vec<Response>> {
+        let messages = receive(&self.socket, &mut self.channel, &mut self.buf)?;
+        let mut responses = Vec::new();
+        for message in messages {
+            //messages which can't be read are dropped
+            match self.hello.decode(&message) {
+                Err(_) => (),
+                Ok(Response::Event(Event::Pack(Pack::Hash(hash)))) => self.sync(hash)?,
+                Ok(Response::Event(Event::Pack(Pack::Definitions(definitions)))) => {
+                    //without a cache the definitions are fetched again next time
+                    let _ = definitions.cache(&self.cache);
+                    self.definitions = Some(definitions);
+                }
+                Ok(response) => responses.push(response),
+            }
+        }
+        flush(&self.socket, &mut self.channel)?;
+        Ok(responses)
+    }
+
+    //Looks for the definitions whose hash the server sent in the cache, asking the server for them if they aren't there.
+    fn sync(&mut self, hash: u64) -> io::Result<()> {
+        if self.definitions.as_ref().map(Definitions::hash) == Some(hash) {
+            return Ok(());
+        }
+        self.definitions = Definitions::cached(&self.cache, hash);
+        if self.definitions.is_none() {
+            self.send(&Response::Event(Event::Pack(Pack::GetDefinitions)))?;
+        }
+        Ok(())
+    }
+}
+
+//Reads the datagrams waiting on the socket, returning the messages they complete.
+//This also queues what the server didn't acknowledge in time to be sent again.
+fn receive(socket: &UdpSocket, channel: &mut Channel, buf: &mut [u8]) -> io::Result<Vec<Vec<u8>>> {
+    let mut messages = Vec::new();
+    loop {
+        let read_to = match socket.recv(buf) {
+            Ok(read_to) => read_to,
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+            Err(error) => return Err(error),
+        };
+        //malformed datagrams are dropped, the server will send them again
+        if let Ok(received) = channel.receive(&buf[..read_to], Instant::now()) {
+            messages.extend(received);
+        }
+    }
+    channel
+        .tick(Instant::now())
+        .map_err(|error| io::Error::new(io::ErrorKind::TimedOut, error))?;
+    Ok(messages)
+}
+
+fn flush(socket: &UdpSocket, channel: &mut Channel) -> io::Result<()> {
+    for datagram in channel.datagrams() {
+        socket.send(&datagram)?;
+    }
+    Ok(())
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{DataPack, Exception, Game, Topology};
+    use std::path::Path;
+
+    //A server which agrees with what the client asks for, then echoes its messages.
+    //If it has `definitions` it sends them when asked instead.
+    fn serve(socket: UdpSocket, definitions: Option<Definitions>) {
+        let mut channel = Channel::new();
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let mut hello: Option<Hello> = None;
+        loop {
+            let (read_to, addr) = socket.recv_from(&mut buf).unwrap();
+            for message in channel.receive(&buf[..read_to], Instant::now()).unwrap() {
+                let reply = match hello.as_ref() {
+                    None => match Json.decode(&message).unwrap() {
+                        Response::Hello(greeting) => {
+                            let reply = greeting.reply(CAPABILITIES).unwrap();
+                            hello = Some(reply.clone());
+                            Json.encode(&Response::Hello(reply)).unwrap()
+                        }
+                        _ => panic!("the client didn't say hello"),
+                    },
+                    Some(hello) => match (hello.decode(&message).unwrap(), definitions.as_ref()) {
+                        (Response::Event(Event::Pack(Pack::GetDefinitions)), Some(definitions)) => {
+                            let reply = Event::Pack(Pack::Definitions(definitions.clone()));
+                            hello.encode(&Response::Event(reply)).unwrap()
+                        }
+                        (response, _) => hello.encode(&response).unwrap(),
+                    },
+                };
+                channel.send(&reply, Instant::now()).unwrap();
+            }
+            for datagram in channel.datagrams() {
+                socket.send_to(&datagram, addr).unwrap();
+            }
+        }
+    }
+
+    fn server(definitions: Option<Definitions>) -> std::net::SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || serve(socket, definitions));
+        addr
+    }
+
+    //polls until `count` responses were received
+    fn wait(connection: &mut Connection, count: usize) -> Vec<Response> {
+        let mut received = Vec::new();
+        while received.len() < count {
+            received.extend(connection.poll().unwrap());
+            thread::sleep(HANDSHAKE_POLL);
+        }
+        received
+    }
+
+    #[test]
+    fn test_handshake() {
+        let cache =
+            std::env::temp_dir().join(format!("idle-crawler-handshake-{}", std::process::id()));
+        let mut connection = Connection::connect(server(None), &cache).unwrap();
+        assert_eq!(connection.hello.capabilities, CAPABILITIES);
+        let exception = Response::Exception(Exception::LoggedOut);
+        connection.send(&exception).unwrap();
+        connection.send(&Response::Event(Event::Connected)).unwrap();
+        assert!(matches!(
+            wait(&mut connection, 2).as_slice(),
+            [
+                Response::Exception(Exception::LoggedOut),
+                Response::Event(Event::Connected)
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_definitions() {
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("../data");
+        let definitions =
+            Game::new(0, 0, Topology::Square, DataPack::load(data).unwrap()).definitions();
+        let hash = Response::Event(Event::Pack(Pack::Hash(definitions.hash())));
+        let cache =
+            std::env::temp_dir().join(format!("idle-crawler-definitions-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&cache);
+
+        //the server echoes the hash, the client doesn't know the definitions and asks for them
+        let mut connection =
+            Connection::connect(server(Some(definitions.clone())), &cache).unwrap();
+        connection.send(&hash).unwrap();
+        while connection.definitions().is_none() {
+            assert!(connection.poll().unwrap().is_empty());
+            thread::sleep(HANDSHAKE_POLL);
+        }
+        assert_eq!(connection.definitions(), Some(&definitions));
+        let cached = Definitions::cached(&cache, definitions.hash());
+
+        //a server which can't send them, the client finds them in its cache without asking
+        let mut connection = Connection::connect(server(None), &cache).unwrap();
+        connection.send(&hash).unwrap();
+        connection.send(&Response::Event(Event::Connected)).unwrap();
+        let received = wait(&mut connection, 1);
+        std::fs::remove_dir_all(&cache).unwrap();
+        assert_eq!(cached, Some(definitions.clone()));
+        assert!(matches!(
+            received.as_slice(),
+            [Response::Event(Event::Connected)]
+        ));
+        assert_eq!(connection.definitions(), Some(&definitions));
+    }
+}
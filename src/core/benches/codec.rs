@@ -0,0 +1,105 @@
+//! Size and speed of the codecs on typical messages.
+//!
+//! `cargo bench -p core --bench codec` prints how many bytes each message takes with each codec before timing them.
+use core::response::{Action, Binary, Codec, Event, Json, Response, World};
+use core::{BuildingID, Offer, Position, ResourceEntry, ResourceID, Slots, Tile};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::time::Duration;
+
+fn messages() -> Vec<(&'static str, Response)> {
+    let pos = Position { x: -12, y: 7 };
+    let mut tile = Tile::new();
+    tile.biome = "forest".to_string();
+    for (resource, amount) in [(0, 2400), (1, 800), (2, 1500), (3, 300)].iter() {
+        let resource = ResourceID(*resource);
+        tile.resources.amounts.insert(resource, *amount);
+        tile.resources.capacity.insert(resource, *amount);
+    }
+    for building in 0..3 {
+        tile.resources
+            .slots
+            .insert(BuildingID(building), Slots { used: 2, total: 8 });
+    }
+    tile.players = vec!["alice".to_string(), "bob".to_string()];
+    let offer = Offer {
+        offering: vec![ResourceEntry {
+            id: ResourceID(0),
+            amount: 200,
+        }],
+        requesting: vec![
+            ResourceEntry {
+                id: ResourceID(1),
+                amount: 50,
+            },
+            ResourceEntry {
+                id: ResourceID(2),
+                amount: 80,
+            },
+        ],
+    };
+    vec![
+        (
+            "Action::Build",
+            Event::Player(Action::Build {
+                pos,
+                building: BuildingID(1),
+                amount: 3,
+            }),
+        ),
+        (
+            "Action::Hire",
+            Event::Player(Action::Hire {
+                building: BuildingID(1),
+                amount: 5,
+            }),
+        ),
+        (
+            "Action::Trade",
+            Event::Player(Action::Trade {
+                from: "alice".to_string(),
+                to: "bob".to_string(),
+                offer,
+            }),
+        ),
+        ("World::GetTile", Event::World(World::GetTile(pos))),
+        ("World::Tile", Event::World(World::Tile { pos, tile })),
+        (
+            "World::Exhausted",
+            Event::World(World::Exhausted {
+                pos,
+                resource: ResourceID(1),
+            }),
+        ),
+    ]
+    .into_iter()
+    .map(|(name, event)| (name, Response::Event(event)))
+    .collect()
+}
+
+fn codecs(c: &mut Criterion) {
+    let codecs: [(&str, &dyn Codec); 2] = [("json", &Json), ("binary", &Binary)];
+    let messages = messages();
+    println!("{:<20}{:>8}{:>8}", "bytes", "json", "binary");
+    for (name, response) in &messages {
+        let [json, binary] = codecs.map(|(_, codec)| codec.encode(response).unwrap().len());
+        println!("{:<20}{:>8}{:>8}", name, json, binary);
+    }
+
+    for (codec_name, codec) in codecs.iter() {
+        let mut group = c.benchmark_group(*codec_name);
+        group.measurement_time(Duration::from_secs(2));
+        for (name, response) in &messages {
+            group.bench_function(*name, |b| {
+                b.iter(|| {
+                    codec
+                        .decode(&codec.encode(black_box(response)).unwrap())
+                        .unwrap()
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, codecs);
+criterion_main!(benches);
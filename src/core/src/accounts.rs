@@ -0,0 +1,391 @@
+//! Who can play and who is playing.
+//!
+//! Players register once and receive a random `Token` which they log in with afterwards. The server only keeps
+//! a hash of the tokens, so that a leaked accounts file doesn't let anyone log in.
+use crate::player::Username;
+use crate::response::Exception;
+use anyhow::{Context, Result};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Logged in players who don't send anything for this long have to log in again.
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// A token used as a pass for a user.
+///
+/// It is 32 random bytes, written in hexadecimal. Its `Debug` output hides it so that it doesn't end up in logs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Token([u8; 32]);
+
+impl Token {
+    /// Draws a token from the random source of the operating system.
+    ///
+    /// # Panics
+    /// If the operating system has no random source, there's no way to make tokens that can't be guessed.
+    pub fn random() -> Token {
+        let mut bytes = [0; 32];
+        getrandom::getrandom(&mut bytes).expect("no random source to make tokens with");
+        Token(bytes)
+    }
+
+    fn hash(&self) -> Secret {
+        Secret(Sha256::digest(self.0).into())
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Token(..)")
+    }
+}
+
+impl Serialize for Token {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Token {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Token, D::Error> {
+        from_hex(deserializer).map(Token)
+    }
+}
+
+//The hash of a token, which is what the server stores.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Secret([u8; 32]);
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Secret, D::Error> {
+        from_hex(deserializer).map(Secret)
+    }
+}
+
+fn from_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+    let text = String::deserialize(deserializer)?;
+    let mut bytes = [0; 32];
+    hex::decode_to_slice(&text, &mut bytes).map_err(D::Error::custom)?;
+    Ok(bytes)
+}
+
+/// The registered players, saved to a JSON file whenever one registers.
+#[derive(Debug)]
+pub struct Accounts {
+    path: PathBuf,
+    secrets: HashMap<Username, Secret>,
+}
+
+impl Accounts {
+    /// Reads the accounts saved at `path`. There are none if the file doesn't exist yet.
+    ///
+    /// Older servers stored the numbers players logged in with rather than hashed tokens.
+    /// These accounts are dropped with a warning, their players have to register again.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Accounts> {
+        let path = path.as_ref();
+        let entries: HashMap<Username, Value> = match fs::read(path) {
+            Ok(file) => serde_json::from_slice(&file)
+                .with_context(|| format!("couldn't read the accounts of {}", path.display()))?,
+            Err(error) if error.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(error) => return Err(error.into()),
+        };
+        let mut secrets = HashMap::new();
+        for (username, entry) in entries {
+            if entry.is_u64() {
+                println!(
+                    "Dropped the account of {} from {}: it predates hashed tokens",
+                    username,
+                    path.display()
+                );
+                continue;
+            }
+            let secret = serde_json::from_value(entry).with_context(|| {
+                format!(
+                    "couldn't read the account of {} in {}",
+                    username,
+                    path.display()
+                )
+            })?;
+            secrets.insert(username, secret);
+        }
+        Ok(Accounts {
+            path: path.to_path_buf(),
+            secrets,
+        })
+    }
+
+    /// Registers `username` and saves the accounts. Returns the token the player logs in with.
+    ///
+    /// # Errors
+    /// `Exception::AlreadyRegistered` if the username is taken. If the accounts can't be saved the player isn't registered.
+    pub fn register(&mut self, username: &str) -> Result<Token> {
+        if self.secrets.contains_key(username) {
+            return Err(Exception::AlreadyRegistered.into());
+        }
+        let token = Token::random();
+        self.secrets.insert(username.to_string(), token.hash());
+        if let Err(error) = self.save() {
+            self.secrets.remove(username);
+            return Err(error);
+        }
+        Ok(token)
+    }
+
+    /// Checks that `token` is the one `username` was given when registering.
+    pub fn verify(&self, username: &str, token: &Token) -> Result<(), Exception> {
+        match self.secrets.get(username) {
+            Some(secret) if *secret == token.hash() => Ok(()),
+            Some(_) => Err(Exception::InvalidToken),
+            None => Err(Exception::Unregistered),
+        }
+    }
+
+    //Written to a temporary file first, like saves, so that a crash can't lose every account.
+    fn save(&self) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file =
+            File::create(&tmp).with_context(|| format!("couldn't create {}", tmp.display()))?;
+        file.write_all(&serde_json::to_vec(&self.secrets)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("couldn't write {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+//A player logged in on a peer.
+#[derive(Debug)]
+struct Login {
+    username: Username,
+    last_active: Instant,
+}
+
+/// Which player is logged in on which peer, `P` being how the server tells peers apart.
+///
+/// A player is logged in on one peer at most.
+#[derive(Debug)]
+pub struct Sessions<P> {
+    logins: HashMap<P, Login>,
+    //how long players stay logged in without sending anything
+    timeout: Duration,
+}
+
+impl<P: Copy + Eq + Hash> Sessions<P> {
+    pub fn new() -> Sessions<P> {
+        Sessions::with_timeout(SESSION_TIMEOUT)
+    }
+
+    /// Sessions which expire after `timeout` instead of `SESSION_TIMEOUT`.
+    pub fn with_timeout(timeout: Duration) -> Sessions<P> {
+        Sessions {
+            logins: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Logs `username` in on `peer`, in place of whoever was logged in there.
+    ///
+    /// Returns the other peer the player was logged in on, which is logged out.
+    pub fn login(&mut self, peer: P, username: Username, now: Instant) -> Option<P> {
+        let previous = self.peer(&username).filter(|previous| *previous != peer);
+        if let Some(previous) = previous {
+            self.logins.remove(&previous);
+        }
+        self.logins.insert(
+            peer,
+            Login {
+                username,
+                last_active: now,
+            },
+        );
+        previous
+    }
+
+    /// Logs out the player of `peer`, if any.
+    pub fn logout(&mut self, peer: &P) -> Option<Username> {
+        self.logins.remove(peer).map(|login| login.username)
+    }
+
+    /// The player logged in on `peer`.
+    ///
+    /// # Errors
+    /// `Exception::LoggedOut` if nobody is.
+    pub fn user(&self, peer: &P) -> Result<&Username, Exception> {
+        self.logins
+            .get(peer)
+            .map(|login| &login.username)
+            .ok_or(Exception::LoggedOut)
+    }
+
+    /// The peer `username` is logged in on.
+    pub fn peer(&self, username: &str) -> Option<P> {
+        self.logins
+            .iter()
+            .find(|(_, login)| login.username == username)
+            .map(|(peer, _)| *peer)
+    }
+
+    /// Keeps the session of `peer` alive.
+    pub fn touch(&mut self, peer: &P, now: Instant) {
+        if let Some(login) = self.logins.get_mut(peer) {
+            login.last_active = now;
+        }
+    }
+
+    /// Logs out the players who weren't active for the timeout of the sessions, and returns them.
+    pub fn expire(&mut self, now: Instant) -> Vec<(P, Username)> {
+        let expired: Vec<P> = self
+            .logins
+            .iter()
+            .filter(|(_, login)| now.duration_since(login.last_active) >= self.timeout)
+            .map(|(peer, _)| *peer)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|peer| self.logout(&peer).map(|username| (peer, username)))
+            .collect()
+    }
+}
+
+impl<P: Copy + Eq + Hash> Default for Sessions<P> {
+    fn default() -> Self {
+        Sessions::new()
+    }
+}
+
+#[cfg(test)]
+impl Token {
+    //A token which is the same every time, for fixtures.
+    pub(crate) fn fixed() -> Token {
+        Token([7; 32])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::dir;
+
+    fn exception(error: anyhow::Error) -> Exception {
+        error.downcast().unwrap()
+    }
+
+    #[test]
+    fn test_token() {
+        let token = Token::random();
+        assert_ne!(token, Token::random());
+        let json = serde_json::to_string(&token).unwrap();
+        assert_eq!(json.len(), 66);
+        assert_eq!(serde_json::from_str::<Token>(&json).unwrap(), token);
+        assert_eq!(format!("{:?}", token), "Token(..)");
+        assert!(serde_json::from_str::<Token>("3421545").is_err());
+        assert!(serde_json::from_str::<Token>(r#""0707""#).is_err());
+    }
+
+    #[test]
+    fn test_register() {
+        let dir = dir("register");
+        let path = dir.join("accounts.json");
+        let mut accounts = Accounts::load(&path).unwrap();
+        let token = accounts.register("alice").unwrap();
+        assert!(matches!(
+            exception(accounts.register("alice").unwrap_err()),
+            Exception::AlreadyRegistered
+        ));
+        assert!(accounts.verify("alice", &token).is_ok());
+        assert!(matches!(
+            accounts.verify("alice", &Token::random()),
+            Err(Exception::InvalidToken)
+        ));
+        assert!(matches!(
+            accounts.verify("bob", &token),
+            Err(Exception::Unregistered)
+        ));
+
+        //only the hash of the token is saved
+        let file = fs::read_to_string(&path).unwrap();
+        let accounts = Accounts::load(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!file.contains(&hex::encode(token.0)));
+        assert!(accounts.verify("alice", &token).is_ok());
+        assert!(matches!(
+            accounts.verify("alice", &Token::random()),
+            Err(Exception::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn test_unsaved() {
+        //the directory of the accounts doesn't exist
+        let dir = dir("unsaved");
+        let path = dir.join("missing").join("accounts.json");
+        let mut accounts = Accounts::load(&path).unwrap();
+        assert!(accounts.register("alice").is_err());
+        assert!(matches!(
+            accounts.verify("alice", &Token::random()),
+            Err(Exception::Unregistered)
+        ));
+
+        let path = dir.join("accounts.json");
+        fs::write(&path, r#"{"alice":"0707"}"#).unwrap();
+        let invalid = Accounts::load(&path);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_outdated() {
+        //the accounts of a server which stored plain numbers
+        let dir = dir("outdated");
+        let path = dir.join("accounts.json");
+        fs::write(&path, r#"{"alice":3421545}"#).unwrap();
+        let mut accounts = Accounts::load(&path).unwrap();
+        assert!(matches!(
+            accounts.verify("alice", &Token::random()),
+            Err(Exception::Unregistered)
+        ));
+        let token = accounts.register("alice").unwrap();
+        let accounts = Accounts::load(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(accounts.verify("alice", &token).is_ok());
+    }
+
+    #[test]
+    fn test_sessions() {
+        let start = Instant::now();
+        let mut sessions = Sessions::new();
+        assert!(matches!(sessions.user(&1), Err(Exception::LoggedOut)));
+        assert_eq!(sessions.login(1, "alice".to_string(), start), None);
+        assert_eq!(sessions.user(&1).unwrap(), "alice");
+        //logging in again on the same peer changes nothing
+        assert_eq!(sessions.login(1, "alice".to_string(), start), None);
+        //logging in from elsewhere replaces the session
+        assert_eq!(sessions.login(2, "alice".to_string(), start), Some(1));
+        assert!(matches!(sessions.user(&1), Err(Exception::LoggedOut)));
+        assert_eq!(sessions.peer("alice"), Some(2));
+        assert_eq!(sessions.login(3, "bob".to_string(), start), None);
+
+        let later = start + SESSION_TIMEOUT;
+        sessions.touch(&3, start + Duration::from_secs(60));
+        assert_eq!(sessions.expire(later), vec![(2, "alice".to_string())]);
+        assert!(matches!(sessions.user(&2), Err(Exception::LoggedOut)));
+        assert_eq!(sessions.user(&3).unwrap(), "bob");
+        assert_eq!(sessions.logout(&3), Some("bob".to_string()));
+        assert_eq!(sessions.logout(&3), None);
+        assert!(sessions.expire(later + SESSION_TIMEOUT).is_empty());
+    }
+}
@@ -1,82 +1,172 @@
 use crate::resources::ResourceID;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::Path;
 
 ///A u8 that represents a unique Building.
-#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct BuildingID(pub u8);
 
 ///Core component of a player's empire. Generates its resources.
 ///
 /// This is more of a "template" than a building as conceived by the user.
 /// Mainly be used to determine the potency of a Player building.
-#[derive(Debug, Deserialize, Serialize)]
+///
+/// In the data files a building is either a list of its fields, in order, or an object of named fields.
+/// Objects may leave out the fields which are empty (or false).
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Building {
     ///Name of the building. Used to enlighten the player as to the building's purpose.
     pub name: String,
     /// True if it extracts raw resources from Tile resource patches.
+    #[serde(default)]
     pub extractor: bool,
     /// All buildings that must be uncloked before this one.
+    #[serde(default)]
     pub prerequisites: Vec<BuildingID>,
     /// The resources produced by the buidling.
+    #[serde(default)]
     pub produced: HashMap<ResourceID, u32>,
     /// The resources consumed by the building.
+    #[serde(default)]
     pub consumed: HashMap<ResourceID, u32>,
     /// The maximum number of workers the building can hire.
+    #[serde(default)]
     pub max_workers: u32,
     /// Resources used to create the building.
+    #[serde(default)]
     pub construction_cost: HashMap<ResourceID, u32>,
+    /// Extra room the building gives to the stockpiles of the player.
+    #[serde(default)]
+    pub storage: HashMap<ResourceID, u32>,
 }
 
 ///Unique mapping of all BuildindID and their corresponding Building.
 pub type AllBuildings = HashMap<BuildingID, Building>;
 
-///This struct enables Generator from the player module to create GenMap.
+///Links every resource to the buildings that make it and to the ones that use it.
 ///
-///It maps all buildings to the resources they consume to produce their goods. This means that a building will be maped to as many keys (ResourceID) as resources it consumes.
-///This is the most optimal way that was found to generate the GenMaps and then the resources. This is not meant to be generated manually but read from the game data by serde_json.
-///It was called DependencyTree because it serves a somewhat similar purpose. However it does differ in design a bit as a branch does not lead to another. Rather all branch "sprout" are keys. This is however enough and enables faster and simpler operations.
-//The first value is a map where the values are vecs of building (IDs) that depend on the resource key (ID)
-//The second is collection of Building which have no deps.
-#[derive(Debug)]
-pub struct DependencyTree(
-    //The tree-like mapping. Where all sprouts are placed along-side one another.
-    pub HashMap<ResourceID, Vec<BuildingID>>,
-    //Some buildings do not consume any resource. This means that they wouldn't be taken into account by the Generator. To prevent this and reduce the slight offset caused by the tree in these buildings this "free" field is used and is always handled first.
-    pub Vec<BuildingID>,
-);
-impl DependencyTree {
-    fn new(buildings: &AllBuildings) -> DependencyTree {
-        let mut tree: HashMap<ResourceID, Vec<BuildingID>> = HashMap::new();
-        //This Vec holds all buildings without deps. This way they can be handled first and not stall the logic.
-        //This also allowing gives a perf boost.
-        let mut free = Vec::new();
-        for (name, building) in buildings.iter() {
-            if building.consumed.len() > 0 {
-                for (resource, _) in building.consumed.iter() {
-                    tree.entry(*resource).or_default().push(*name);
-                }
-            } else {
-                free.push(*name);
+///This is what lets the production solver find how a shortage propagates along production chains.
+///It is not meant to be generated manually but built from the game data once it has been read by serde_json.
+#[derive(Debug, Default)]
+pub struct DependencyGraph {
+    ///Buildings producing each resource, sorted by ID.
+    pub producers: HashMap<ResourceID, Vec<BuildingID>>,
+    ///Buildings consuming each resource, sorted by ID.
+    pub consumers: HashMap<ResourceID, Vec<BuildingID>>,
+    ///Buildings which don't consume anything and thus never wait on another one.
+    pub free: Vec<BuildingID>,
+    //circular recipes, found once when the graph is built
+    cycles: Vec<Vec<BuildingID>>,
+}
+
+impl DependencyGraph {
+    pub(crate) fn new(buildings: &AllBuildings) -> DependencyGraph {
+        let mut graph = DependencyGraph::default();
+        for (id, building) in buildings.iter() {
+            for resource in building.produced.keys() {
+                graph.producers.entry(*resource).or_default().push(*id);
             }
+            for resource in building.consumed.keys() {
+                graph.consumers.entry(*resource).or_default().push(*id);
+            }
+            if building.consumed.is_empty() {
+                graph.free.push(*id);
+            }
+        }
+        for list in graph
+            .producers
+            .values_mut()
+            .chain(graph.consumers.values_mut())
+            .chain(std::iter::once(&mut graph.free))
+        {
+            list.sort();
         }
-        DependencyTree(tree, free)
+        graph.cycles = graph.find_cycles(buildings);
+        graph
     }
-}
 
-/// Abstracts data into rust structs
-///
-/// This method depends on the data crate. The latter provides the game data that
-/// determines the options given to the player.
-///
-/// To keep it easy to maintain and assert interoperability the data is in JSON
-/// and abstracted into rust structs by this method.
-pub fn load_buildings<P: AsRef<Path>>(path: P) -> (AllBuildings, DependencyTree) {
-    let file = std::fs::read(path).expect("couldn't read buildings.json");
-    let data: AllBuildings =
-        serde_json::from_slice(&file).expect("couldn't serialize buildings JSON");
+    ///Buildings using what `building` produces.
+    pub fn dependents(&self, building: &Building) -> Vec<BuildingID> {
+        let mut dependents: Vec<BuildingID> = building
+            .produced
+            .keys()
+            .filter_map(|resource| self.consumers.get(resource))
+            .flatten()
+            .copied()
+            .collect();
+        dependents.sort();
+        dependents.dedup();
+        dependents
+    }
+
+    ///Groups of buildings whose recipes feed one another in a loop.
+    ///
+    ///A building consuming what it produces is a loop of its own.
+    pub fn cycles(&self) -> &[Vec<BuildingID>] {
+        &self.cycles
+    }
 
-    let tree = DependencyTree::new(&data);
-    (data, tree)
+    ///Whether `id` is part of a circular recipe.
+    pub fn in_cycle(&self, id: &BuildingID) -> bool {
+        self.cycles.iter().any(|cycle| cycle.contains(id))
+    }
+
+    //Tarjan's algorithm: every strongly connected component of more than one building is a cycle.
+    fn find_cycles(&self, buildings: &AllBuildings) -> Vec<Vec<BuildingID>> {
+        struct State {
+            index: HashMap<BuildingID, usize>,
+            low: HashMap<BuildingID, usize>,
+            stack: Vec<BuildingID>,
+            cycles: Vec<Vec<BuildingID>>,
+        }
+
+        fn visit(
+            id: BuildingID,
+            graph: &DependencyGraph,
+            buildings: &AllBuildings,
+            state: &mut State,
+        ) {
+            let index = state.index.len();
+            state.index.insert(id, index);
+            state.low.insert(id, index);
+            state.stack.push(id);
+            let mut looped = false;
+            for next in graph.dependents(&buildings[&id]) {
+                looped |= next == id;
+                if !state.index.contains_key(&next) {
+                    visit(next, graph, buildings, state);
+                    let low = state.low[&id].min(state.low[&next]);
+                    state.low.insert(id, low);
+                } else if state.stack.contains(&next) {
+                    let low = state.low[&id].min(state.index[&next]);
+                    state.low.insert(id, low);
+                }
+            }
+            if state.low[&id] == index {
+                let at = state.stack.iter().rposition(|crt| *crt == id).unwrap();
+                let mut component = state.stack.split_off(at);
+                if component.len() > 1 || looped {
+                    component.sort();
+                    state.cycles.push(component);
+                }
+            }
+        }
+
+        let mut ids: Vec<&BuildingID> = buildings.keys().collect();
+        ids.sort();
+        let mut state = State {
+            index: HashMap::new(),
+            low: HashMap::new(),
+            stack: Vec::new(),
+            cycles: Vec::new(),
+        };
+        for id in ids {
+            if !state.index.contains_key(id) {
+                visit(*id, self, buildings, &mut state);
+            }
+        }
+        state.cycles.sort();
+        state.cycles
+    }
 }
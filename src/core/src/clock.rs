@@ -122,6 +122,22 @@ impl Clock {
     }
 }
 
+/// Number of ticks that fit in `elapsed` at `ups` Updates Per Second.
+///
+/// Time beyond `cap` is ignored, so that an idle game never has to catch up on too long an absence.
+///
+/// # Example
+/// ```
+///# use core::clock::ticks;
+///# use std::time::Duration;
+/// let cap = Duration::from_secs(60);
+/// assert_eq!(ticks(Duration::from_millis(2500), 2, cap), 5);
+/// assert_eq!(ticks(Duration::from_secs(3600), 2, cap), 120);
+///```
+pub fn ticks(elapsed: Duration, ups: u8, cap: Duration) -> u64 {
+    (elapsed.min(cap).as_millis() * ups as u128 / 1000) as u64
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
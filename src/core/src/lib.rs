@@ -1,31 +1,50 @@
+pub mod accounts;
 mod buildings;
 pub mod clock;
+pub mod net;
+pub mod pack;
 mod player;
+pub mod population;
 mod pos;
+pub mod production;
+pub mod research;
 pub mod resources;
 pub mod response;
+pub mod save;
+#[cfg(test)]
+mod test_utils;
 mod tile;
-pub(crate) mod trade;
-pub use self::buildings::{load_buildings, AllBuildings, Building, BuildingID, DependencyTree};
+pub mod trade;
+mod world;
+pub use self::buildings::{AllBuildings, Building, BuildingID, DependencyGraph};
 use self::clock::Clock;
+pub use self::pack::{DataPack, Definitions, PackError, PackInfo, Problem};
 pub use self::player::{Generator, Player, Username};
-pub use self::pos::PosGenerator;
-pub use self::resources::{load_resources, AllResources, ResourceID};
-pub use self::response::{Action, Event, Exception, Response, World};
-pub use self::tile::{Position, Tile};
+pub use self::population::{Population, PopulationRules};
+pub use self::pos::{PosGenerator, Topology};
+pub use self::research::{Research, ResearchRules};
+pub use self::resources::{AllResources, ResourceID};
+pub use self::response::{
+    Action, Binary, Capability, Codec, Deal, Economy, Event, Exception, Hello, Json, Pack,
+    Response, World, PROTOCOL_VERSION,
+};
+use self::save::{Autosave, Format};
+use self::tile::by_position;
+pub use self::tile::{Position, Slots, Tile};
+pub use self::trade::{Offer, ResourceEntry};
+pub use self::world::{Biome, Patch, WorldGenerator, WorldRules};
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::{write, File};
-use std::io;
-use std::io::Read;
-use std::path::PathBuf;
-use std::sync::mpsc::{Receiver, Sender};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
 use std::thread;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const BUILDINGS_PATH: &str = "data/buildings.json";
-const RESOURCES_PATH: &str = "data/resources.json";
+/// Directory holding the game data, relative to where the game is ran.
+pub const DATA_DIR: &str = "data";
+//how long an absence players are compensated for by default
+const OFFLINE_CAP: Duration = Duration::from_secs(12 * 3600);
 
 /// Data to be saved and represeting the current game
 ///
@@ -36,9 +55,20 @@ const RESOURCES_PATH: &str = "data/resources.json";
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GameData {
     //we need to make sure they stay ordered to enable correct behavior of PosGenerator
+    #[serde(with = "by_position")]
     pub world: HashMap<Position, Tile>,
     pub players: HashMap<Username, Player>,
     pos_gen: PosGenerator,
+    //the world is entirely determined by the seed and the world rules
+    seed: u64,
+    //number of ticks simulated since the game was created
+    #[serde(default)]
+    tick: u64,
+    //when the last tick was simulated, in milliseconds since the UNIX epoch
+    #[serde(default)]
+    time: u64,
+    //the data packs the game is played with, which are required to resume it
+    packs: Vec<PackInfo>,
 }
 
 #[derive(Debug)]
@@ -46,28 +76,76 @@ pub struct Game {
     pub data: GameData,
     buildings: AllBuildings,
     resources: AllResources,
-    dep_tree: DependencyTree,
+    dep_graph: DependencyGraph,
+    //hash of the definitions sent to clients, see `Game::definitions`
+    definitions_hash: u64,
+    generator: WorldGenerator,
+    research: ResearchRules,
+    population: PopulationRules,
+    //responses meant for other players than the one whose event is being processed
+    outbox: Vec<(Username, Response)>,
+    //only the empires of connected players are simulated every tick, the others catch up when they reconnect
+    online: HashSet<Username>,
+    ups: u8,
+    offline_cap: Duration,
+    autosave: Option<Autosave>,
 }
 
 impl Game {
+    /// Runs the game until the binary hosting it hangs up, that is until `receiver` is disconnected.
+    ///
+    /// When autosaving is enabled the game is saved one last time before returning, see `Game::set_autosave`.
+    ///
+    /// # Errors
+    /// The game stops when an autosave fails, so that the binary hosting it can report it and shut down.
     pub fn run(
         &mut self,
         ups: u8,
-        sender: Sender<(Username, Exception)>,
+        sender: Sender<(Username, Response)>,
         receiver: Receiver<(Username, Event)>,
+    ) -> Result<()> {
+        let outcome = self.serve(ups, &sender, &receiver);
+        self.autosave()?;
+        outcome
+    }
+
+    fn serve(
+        &mut self,
+        ups: u8,
+        sender: &Sender<(Username, Response)>,
+        receiver: &Receiver<(Username, Event)>,
     ) -> Result<()> {
         let mut i = 0;
         let mut clock = Clock::new(ups);
+        let mut saved = Instant::now();
+        self.ups = ups;
+        self.resume();
         loop {
             i += 1;
-            //processing events received from the master thread
-            for event in receiver.try_iter() {
-                match self.process(&event.0, event.1) {
-                    Ok(_) => (),
-                    Err(exception) => sender.send((event.0, exception))?,
+            //processing events received from the master thread, until it hangs up
+            loop {
+                let (username, event) = match receiver.try_recv() {
+                    Ok(received) => received,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                };
+                match self.process(&username, event) {
+                    Ok(Some(event)) => sender.send((username, Response::Event(event)))?,
+                    Ok(None) => (),
+                    Err(exception) => sender.send((username, Response::Exception(exception)))?,
                 }
             }
+            for notification in self.outbox.drain(..) {
+                sender.send(notification)?;
+            }
             self.update()?;
+            if let Some(interval) = self.autosave.as_ref().map(|autosave| autosave.interval) {
+                if saved.elapsed() >= interval {
+                    //the game stops rather than running on without being saved
+                    self.autosave()?;
+                    saved = Instant::now();
+                }
+            }
             thread::sleep(clock.tick());
             println!("\nIteration {:?}", i);
             println!("Players {:?}", self.get_players());
@@ -86,87 +164,394 @@ impl Game {
         &self.resources
     }
 
-    pub fn save(&self, path: PathBuf) -> Result<(), io::Error> {
-        // create a save file with the current time as filename
-        let now = SystemTime::now();
-        write(
-            path.join(
-                now.duration_since(UNIX_EPOCH)
-                    .expect("Time shouldn't rewind.")
-                    .as_secs()
-                    .to_string(),
-            )
-            .join(".json"),
-            serde_json::to_string(&self.data)?,
-        )?;
-        Ok(())
+    /// The buildings and resources of the game, as sent to clients.
+    pub fn definitions(&self) -> Definitions {
+        Definitions {
+            manifest: self.data.packs.clone(),
+            buildings: self.buildings.clone(),
+            resources: self.resources.clone(),
+        }
     }
 
-    pub fn load(&self, path: PathBuf) -> Result<Game, io::Error> {
-        let mut file = String::new();
-        File::open(path)?.read_to_string(&mut file)?;
-        let data: GameData = serde_json::from_str(&file)?;
-        let (buildings, tree) = load_buildings(BUILDINGS_PATH);
-        Ok(Game {
-            data,
-            buildings: buildings,
-            dep_tree: tree,
-            resources: load_resources(RESOURCES_PATH),
-        })
+    /// The shape of the map, which rules adjacency between tiles.
+    pub fn topology(&self) -> Topology {
+        self.data.pos_gen.topology()
+    }
+
+    pub fn get_generator(&self) -> &WorldGenerator {
+        &self.generator
     }
 
-    pub fn new(nbr: u32) -> Game {
-        let mut world = HashMap::new();
-        let mut pos_gen = PosGenerator::new(0);
+    /// Saves the game in the `dir` directory, see `save::write`.
+    ///
+    /// The save is named after the current time. Returns the path of the new save.
+    pub fn save<P: AsRef<Path>>(&self, dir: P, format: Format) -> Result<PathBuf> {
+        let path = dir
+            .as_ref()
+            .join(now().to_string())
+            .with_extension(format.extension());
+        save::write(&path, &self.data)?;
+        Ok(path)
+    }
+
+    /// Resumes the game saved at `path` with the rules of `pack`, see `save::read`.
+    ///
+    /// # Errors
+    /// Besides failing to read the save, this fails if `pack` isn't made of the packs the game was played with.
+    pub fn load<P: AsRef<Path>>(path: P, pack: DataPack) -> Result<Game> {
+        let data = save::read(path)?;
+        if data.packs != pack.manifest {
+            let names = |packs: &[PackInfo]| {
+                let names: Vec<String> = packs.iter().map(PackInfo::to_string).collect();
+                names.join(", ")
+            };
+            return Err(anyhow!(
+                "the game was played with the packs {} but {} were loaded",
+                names(&data.packs),
+                names(&pack.manifest)
+            ));
+        }
+        Ok(Game::with_pack(data, pack))
+    }
+
+    /// Creates a new game whose world starts with `nbr` tiles generated from `seed`.
+    ///
+    /// The `topology` decides the shape of the map for the whole game and `pack` its rules.
+    pub fn new(nbr: u32, seed: u64, topology: Topology, pack: DataPack) -> Game {
+        let data = GameData {
+            world: HashMap::new(),
+            players: HashMap::new(),
+            pos_gen: PosGenerator::new(topology),
+            seed,
+            tick: 0,
+            time: now(),
+            packs: pack.manifest.clone(),
+        };
+        let mut game = Game::with_pack(data, pack);
         for _ in 0..nbr {
-            world.insert(pos_gen.next().unwrap(), Tile::new());
+            let pos = game.data.pos_gen.next().unwrap();
+            game.data.world.insert(pos, game.generator.tile(&pos));
         }
-        let (buildings, tree) = load_buildings(BUILDINGS_PATH);
+        game
+    }
+
+    //Runs `data` with the rules of `pack`.
+    fn with_pack(data: GameData, pack: DataPack) -> Game {
+        let definitions = Definitions {
+            manifest: pack.manifest,
+            buildings: pack.buildings,
+            resources: pack.resources,
+        };
+        let definitions_hash = definitions.hash();
         Game {
-            data: GameData {
-                world: world,
-                players: HashMap::new(),
-                pos_gen: pos_gen,
-            },
-            buildings: buildings,
-            dep_tree: tree,
-            resources: load_resources(RESOURCES_PATH),
+            generator: WorldGenerator::new(data.seed, pack.world),
+            data,
+            dep_graph: DependencyGraph::new(&definitions.buildings),
+            definitions_hash,
+            buildings: definitions.buildings,
+            resources: definitions.resources,
+            research: pack.research,
+            population: pack.population,
+            outbox: Vec::new(),
+            online: HashSet::new(),
+            ups: 1,
+            offline_cap: OFFLINE_CAP,
+            autosave: None,
         }
     }
 
     pub fn update(&mut self) -> Result<()> {
-        self.generate()?;
+        self.data.tick += 1;
+        self.data.time = now();
+        let online: Vec<Username> = self.online.iter().cloned().collect();
+        for name in online {
+            self.advance(&name, 1);
+        }
+        for tile in self.data.world.values_mut() {
+            self.generator.regrow(tile);
+        }
         Ok(())
     }
 
-    pub fn generate(&mut self) -> Result<()> {
-        for (_name, player) in self.data.players.iter_mut() {
-            player.generate(&self.buildings, &self.dep_tree);
+    //Simulates `ticks` ticks of a single empire. The world isn't regrown, this is done for all tiles at once.
+    fn advance(&mut self, username: &Username, ticks: u64) {
+        let low_deposit = self.generator.rules().low_deposit;
+        let player = match self.data.players.get_mut(username) {
+            Some(player) => player,
+            None => return,
+        };
+        for event in player.fast_forward(
+            ticks,
+            &self.buildings,
+            &self.dep_graph,
+            &mut self.data.world,
+            low_deposit,
+            &self.population,
+        ) {
+            self.outbox.push((username.clone(), Response::Event(event)));
+        }
+    }
+
+    /// Number of ticks simulated since the game was created.
+    pub fn tick(&self) -> u64 {
+        self.data.tick
+    }
+
+    /// Sets the longest absence, whether of a player or of the whole server, that is made up for.
+    pub fn set_offline_cap(&mut self, cap: Duration) {
+        self.offline_cap = cap;
+    }
+
+    /// Makes the game save itself regularly while it runs, and once more when it stops.
+    pub fn set_autosave(&mut self, autosave: Autosave) {
+        self.autosave = Some(autosave);
+    }
+
+    //Saves the game and only keeps the most recent saves, if autosaving is enabled.
+    fn autosave(&self) -> Result<()> {
+        if let Some(autosave) = self.autosave.as_ref() {
+            std::fs::create_dir_all(&autosave.dir)?;
+            self.save(&autosave.dir, autosave.format)?;
+            save::rotate(&autosave.dir, autosave.keep)?;
         }
         Ok(())
     }
 
+    /// Fast-forwards the empire of a player through the ticks it missed while offline.
+    ///
+    /// At most `offline_cap` worth of ticks are simulated, the rest of the absence is lost.
+    /// Steady production is simulated in large batches, see `Player::fast_forward`.
+    /// Returns the number of ticks that were simulated.
+    pub fn catch_up(&mut self, username: &Username) -> u64 {
+        let behind = match self.data.players.get(username) {
+            Some(player) => self.data.tick.saturating_sub(player.last_tick),
+            None => return 0,
+        };
+        let ticks = behind.min(clock::ticks(self.offline_cap, self.ups, self.offline_cap));
+        self.advance(username, ticks);
+        if let Some(player) = self.data.players.get_mut(username) {
+            player.last_tick = self.data.tick;
+        }
+        ticks
+    }
+
+    //Accounts for the time during which the game wasn't running, like after being loaded.
+    //The world is regrown right away while players catch up once they reconnect.
+    fn resume(&mut self) {
+        let now = now();
+        //saves made before time was recorded can't tell how long the server was down
+        if self.data.time != 0 {
+            let elapsed = Duration::from_millis(now.saturating_sub(self.data.time));
+            let ticks = clock::ticks(elapsed, self.ups, self.offline_cap);
+            self.data.tick += ticks;
+            for tile in self.data.world.values_mut() {
+                self.generator.regrow_for(tile, ticks);
+            }
+        }
+        self.data.time = now;
+    }
+
     pub fn remove_player(&mut self, player: &Username) -> () {
+        if let Some(lands) = self.data.players.get(player).map(|p| p.lands.clone()) {
+            for pos in lands {
+                self.vacate(player, pos);
+            }
+        }
         self.data.players.remove(player);
+        self.online.remove(player);
+    }
+
+    /// Registers `username` as an occupant of the tile at `pos`.
+    ///
+    /// The tile becomes part of the player's lands and the player gets in contact with all other occupants.
+    /// Does nothing if the player already occupies the tile.
+    pub fn occupy(&mut self, username: &Username, pos: Position) {
+        let tile = Game::tile_entry(&mut self.data.world, &self.generator, pos);
+        if tile.players.contains(username) {
+            return;
+        }
+        for other in tile.players.iter() {
+            if let [Some(player), Some(neighbour)] =
+                self.data.players.get_disjoint_mut([username, other])
+            {
+                player.add_contact(other);
+                neighbour.add_contact(username);
+            }
+        }
+        tile.players.push(username.clone());
+        if let Some(player) = self.data.players.get_mut(username) {
+            player.lands.push(pos);
+        }
+    }
+
+    /// Removes `username` from the occupants of the tile at `pos`.
+    ///
+    /// This is the opposite of `Game::occupy`. Contacts are dropped once players share no tile anymore.
+    pub fn vacate(&mut self, username: &Username, pos: Position) {
+        let tile = match self.data.world.get_mut(&pos) {
+            Some(tile) => tile,
+            None => return,
+        };
+        let occupants = tile.players.len();
+        tile.players.retain(|occupant| occupant != username);
+        //a player who didn't live there had no contact through the tile
+        if tile.players.len() == occupants {
+            return;
+        }
+        for other in tile.players.iter() {
+            if let [Some(player), Some(neighbour)] =
+                self.data.players.get_disjoint_mut([username, other])
+            {
+                player.remove_contact(other);
+                neighbour.remove_contact(username);
+            }
+        }
+        if let Some(player) = self.data.players.get_mut(username) {
+            player.lands.retain(|land| *land != pos);
+        }
     }
 
     //this for when a new player is added to the game, not to load one from the save (see Game::load)
+    //The player is given the first unoccupied tile of the spiral as its capital.
     pub fn add_player(&mut self, player: Username) -> Result<&mut Player> {
         if self.data.players.contains_key(&player) {
             Err(anyhow!("Player {} already exists", player))
         } else {
-            self.data
+            self.data.players.insert(player.to_string(), Player::new());
+            let capital = self.free_tile();
+            self.occupy(&player, capital);
+            let new = self.data.players.get_mut(&player).unwrap();
+            new.settle(capital, &self.buildings);
+            new.last_tick = self.data.tick;
+            Ok(new)
+        }
+    }
+
+    //The next position of the spiral nobody lives on. The world expands to it if needed.
+    fn free_tile(&mut self) -> Position {
+        loop {
+            let pos = self.data.pos_gen.next().unwrap();
+            if Game::tile_entry(&mut self.data.world, &self.generator, pos)
                 .players
-                .insert(player.to_string(), Player::new(&self.buildings)); //we only give the buildings for testing purposes
-            Ok(self.data.players.get_mut(&player).unwrap())
+                .is_empty()
+            {
+                return pos;
+            }
         }
     }
 
     /// Gets a tile for any position
     ///
     /// Either returns a reference to an existing tile or makes a new one.
-    pub fn get_tile(&mut self, pos: Position) -> Tile {
-        todo!()
+    /// New tiles are made by the `WorldGenerator` and become part of the world.
+    pub fn get_tile(&mut self, pos: Position) -> &Tile {
+        Game::tile_entry(&mut self.data.world, &self.generator, pos)
+    }
+
+    //The world expands lazily: tiles are only generated once something needs them.
+    //This doesn't borrow the whole Game so that players can be borrowed alongside.
+    fn tile_entry<'a>(
+        world: &'a mut HashMap<Position, Tile>,
+        generator: &WorldGenerator,
+        pos: Position,
+    ) -> &'a mut Tile {
+        world.entry(pos).or_insert_with(|| generator.tile(&pos))
+    }
+
+    /// Queues a response for a player other than the one whose event is being processed.
+    ///
+    /// Queued responses are sent by `Game::run` once all pending events have been processed.
+    pub fn notify(&mut self, username: &Username, response: Response) {
+        self.outbox.push((username.clone(), response));
+    }
+
+    //Borrows the two parties of a trade at once.
+    fn peers(
+        &mut self,
+        from: &Username,
+        to: &Username,
+    ) -> Result<(&mut Player, &mut Player), Exception> {
+        if from == to {
+            return Err(Exception::InvalidOffer);
+        }
+        match self.data.players.get_disjoint_mut([from, to]) {
+            [Some(from), Some(to)] => Ok((from, to)),
+            _ => Err(Exception::Unregistered),
+        }
+    }
+
+    ///Processes trade actions.
+    ///
+    ///The resulting `Deal` is returned to `username` and relayed to the other party.
+    ///Exceptions caused by an existing trade are relayed as well.
+    fn trade(&mut self, username: &Username, action: Action) -> Result<Option<Event>, Exception> {
+        let (peer, outcome) = match action {
+            Action::Trade { from, to, offer } => {
+                let outcome = if from != *username {
+                    Err(Exception::InvalidOffer)
+                } else {
+                    self.peers(&from, &to).and_then(|(player, peer)| {
+                        player.open_trade(&from, (&to, peer), offer.clone())
+                    })
+                };
+                let deal = Deal::Opened {
+                    from,
+                    to: to.clone(),
+                    offer,
+                };
+                (to, outcome.map(|_| deal))
+            }
+            Action::AcceptTrade { from, offer } => {
+                let outcome = self.peers(username, &from).and_then(|(player, peer)| {
+                    player.accept_trade(username, (&from, peer), &offer)
+                });
+                let deal = Deal::Accepted {
+                    from: from.clone(),
+                    to: username.clone(),
+                    offer,
+                };
+                (from, outcome.map(|_| deal))
+            }
+            Action::RefuseTrade { from, offer } => {
+                let outcome = self.peers(username, &from).and_then(|(player, peer)| {
+                    player.refuse_trade(username, (&from, peer), &offer)
+                });
+                let deal = Deal::Refused {
+                    from: from.clone(),
+                    to: username.clone(),
+                    offer,
+                };
+                (from, outcome.map(|_| deal))
+            }
+            Action::CancelTrade { to, offer } => {
+                let outcome = self
+                    .peers(username, &to)
+                    .and_then(|(player, peer)| player.cancel_trade(username, (&to, peer), &offer));
+                let deal = Deal::Cancelled {
+                    from: username.clone(),
+                    to: to.clone(),
+                    offer,
+                };
+                (to, outcome.map(|_| deal))
+            }
+            _ => unreachable!("Game::trade only handles trade actions"),
+        };
+        match outcome {
+            Ok(deal) => {
+                self.notify(&peer, Response::Event(Event::Trade(deal.clone())));
+                Ok(Some(Event::Trade(deal)))
+            }
+            Err(exception) => {
+                //the other party doesn't need to hear about malformed requests
+                if !matches!(
+                    exception,
+                    Exception::InvalidOffer | Exception::Unregistered | Exception::NoContact(_)
+                ) {
+                    self.notify(&peer, Response::Exception(exception.clone()));
+                }
+                Err(exception)
+            }
+        }
     }
 
     ///Processes player action events.
@@ -189,15 +574,40 @@ impl Game {
                     building,
                     amount,
                 } => {
-                    if let Err(_) = player.build(
-                        (&pos, self.data.world.get_mut(&pos).unwrap()),
+                    if !pos.in_range() {
+                        return Err(Exception::Unreachable(pos));
+                    }
+                    //building out of the player's lands claims the tile, which must border them
+                    let claim = !player.lands.contains(&pos);
+                    let claim_cost = &self.generator.rules().claim_cost;
+                    if claim {
+                        let topology = self.data.pos_gen.topology();
+                        if !player
+                            .lands
+                            .iter()
+                            .any(|land| topology.adjacent(land, &pos))
+                        {
+                            return Err(Exception::Unreachable(pos));
+                        }
+                        //paid upfront so that construction can only use what is left
+                        player.pay(claim_cost)?;
+                    }
+                    if let Err(error) = player.build(
+                        (
+                            &pos,
+                            Game::tile_entry(&mut self.data.world, &self.generator, pos),
+                        ),
                         building,
                         self.buildings.get(&building).unwrap(),
                         amount,
                     ) {
-                        //WARN:: this is a placeholder, the actual exception may be different but there's currently no way of determinning it.
-                        return Err(Exception::PlaceHolder);
+                        if claim {
+                            player.refund(claim_cost);
+                        }
+                        //WARN:: not all failures are described by an exception yet, those fall back to a placeholder.
+                        return Err(error.downcast().unwrap_or(Exception::PlaceHolder));
                     } else {
+                        self.occupy(username, pos);
                         Ok(None)
                     }
                 }
@@ -207,21 +617,28 @@ impl Game {
                     building,
                     amount,
                 } => {
-                    if let Err(_) = player.demolish(
-                        (&pos, self.data.world.get_mut(&pos).unwrap()),
-                        building,
-                        self.buildings.get(&building).unwrap(),
-                        amount,
-                    ) {
+                    //demolishing never generates tiles, there's nothing to tear down on those
+                    let tile = match self.data.world.get_mut(&pos) {
+                        Some(tile) => tile,
+                        None => return Err(Exception::TileNotOwned(pos)),
+                    };
+                    let definition = match self.buildings.get(&building) {
+                        Some(definition) => definition,
+                        None => return Err(Exception::NotFound),
+                    };
+                    if let Err(_) = player.demolish((&pos, tile), building, definition, amount) {
                         //WARN:: this is a placeholder, the actual exception may be different but there's currently no way of determinning it.
                         return Err(Exception::PlaceHolder);
                     } else {
+                        if player.buildings_on(&pos) == 0 && player.capital != Some(pos) {
+                            self.vacate(username, pos);
+                        }
                         Ok(None)
                     }
                 }
                 Action::Hire { building, amount } => {
-                    if let Err(_) = player.hire(building, amount) {
-                        return Err(Exception::PlaceHolder);
+                    if let Err(error) = player.hire(building, amount) {
+                        return Err(error.downcast().unwrap_or(Exception::PlaceHolder));
                     } else {
                         Ok(None)
                     }
@@ -247,18 +664,335 @@ impl Game {
                         Ok(None)
                     }
                 }
-                Action::Trade { from, to, offer } => unimplemented!(),
+                Action::Research { building } => {
+                    player.research(building, &self.buildings, &self.research)?;
+                    Ok(None)
+                }
+                Action::Trade { .. }
+                | Action::AcceptTrade { .. }
+                | Action::RefuseTrade { .. }
+                | Action::CancelTrade { .. } => self.trade(username, action),
             },
             Event::World(world) => match world {
                 World::GetTile(pos) => {
                     if player.lands.contains(&pos) {
-                        return Ok(Some(Event::World(World::Tile(self.get_tile(pos)))));
+                        let tile = self.get_tile(pos).clone();
+                        return Ok(Some(Event::World(World::Tile { pos, tile })));
                     } else {
                         return Err(Exception::PlaceHolder);
                     }
                 }
-                World::Tile(tile) => todo!(), //we don't have the position anymore, what do to :thinking:
+                //only ever sent by the game
+                World::Tile { .. } | World::Exhausted { .. } => Err(Exception::PlaceHolder),
+            },
+            Event::Economy(economy) => match economy {
+                Economy::GetReport => Ok(Some(Event::Economy(Economy::Report(
+                    player.economy(&self.dep_graph, &self.buildings),
+                )))),
+                //only ever sent by the game
+                Economy::Report(_) => Err(Exception::PlaceHolder),
+            },
+            Event::Pack(pack) => match pack {
+                Pack::GetDefinitions => {
+                    Ok(Some(Event::Pack(Pack::Definitions(self.definitions()))))
+                }
+                //only ever sent by the game
+                Pack::Hash(_) | Pack::Definitions(_) => Err(Exception::PlaceHolder),
             },
+            Event::Connected => {
+                self.online.insert(username.clone());
+                let caught_up = Event::CaughtUp(self.catch_up(username));
+                //clients which already know the definitions of the game don't have to download them again
+                let hash = Event::Pack(Pack::Hash(self.definitions_hash));
+                self.outbox.push((username.clone(), Response::Event(hash)));
+                Ok(Some(caught_up))
+            }
+            Event::Disconnected => {
+                self.online.remove(username);
+                Ok(None)
+            }
+            //notifications are only ever sent by the game
+            Event::Trade(_) | Event::Unlocked(_) | Event::StockpileFull(_) | Event::CaughtUp(_) => {
+                Err(Exception::PlaceHolder)
+            }
+        }
+    }
+}
+
+//Milliseconds since the UNIX epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time shouldn't rewind.")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{base, dir};
+
+    fn game() -> Game {
+        Game::new(0, 0, Topology::Square, DataPack::load(base()).unwrap())
+    }
+
+    #[test]
+    fn test_catch_up() {
+        let mut game = game();
+        let name = "alice".to_string();
+        game.add_player(name.clone()).unwrap();
+        game.set_offline_cap(Duration::from_secs(60));
+
+        //the game went on for 100 ticks while alice was away, only 60 of them are made up for
+        game.data.tick = 100;
+        let reply = game.process(&name, Event::Connected);
+        assert!(matches!(reply, Ok(Some(Event::CaughtUp(60)))));
+        let alice = &game.data.players[&name];
+        assert_eq!(alice.last_tick, 100);
+        //without food the citizens starved at each of the 3 meals
+        assert_eq!(alice.population().total(), 2);
+
+        let reply = game.process(&name, Event::Connected);
+        assert!(matches!(reply, Ok(Some(Event::CaughtUp(0)))));
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut game = game();
+        let name = "alice".to_string();
+        let capital = game.add_player(name.clone()).unwrap().capital.unwrap();
+        let tile = game.data.world.get_mut(&capital).unwrap();
+        tile.resources.capacity.insert(ResourceID(0), 100);
+        tile.resources.amounts.insert(ResourceID(0), 0);
+        game.set_offline_cap(Duration::from_secs(20));
+
+        //the server was down for 30 seconds, only 20 of them are made up for
+        game.data.time = now() - 30_000;
+        game.resume();
+        assert_eq!(game.tick(), 20);
+        assert_eq!(
+            game.data.world[&capital].resources.amounts[&ResourceID(0)],
+            20
+        );
+        //players catch up once they are back
+        assert_eq!(game.data.players[&name].last_tick, 0);
+        assert_eq!(game.catch_up(&name), 20);
+    }
+
+    #[test]
+    fn test_failed_autosave() {
+        let mut game = game();
+        //the saves can't go in a directory under a file
+        let dir = dir("autosave");
+        let file = dir.join("file");
+        std::fs::write(&file, "not a directory").unwrap();
+        game.set_autosave(Autosave {
+            dir: file.join("saves"),
+            interval: Duration::from_secs(0),
+            keep: 1,
+            format: Format::Json,
+        });
+        let (_events, receiver) = std::sync::mpsc::channel();
+        let (sender, _responses) = std::sync::mpsc::channel();
+        let outcome = game.run(1, sender, receiver);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(outcome.is_err());
+    }
+
+    fn wood(game: &Game, name: &str) -> u32 {
+        game.data.players[name].resources[&ResourceID(0)].current()
+    }
+
+    fn build(pos: Position, amount: u32) -> Event {
+        Event::Player(Action::Build {
+            pos,
+            building: BuildingID(3),
+            amount,
+        })
+    }
+
+    #[test]
+    fn test_capitals() {
+        let mut game = game();
+        let (alice, bob) = ("alice".to_string(), "bob".to_string());
+        game.add_player(alice.clone()).unwrap();
+        game.add_player(bob.clone()).unwrap();
+        let capitals: Vec<Position> = [&alice, &bob]
+            .iter()
+            .map(|name| game.data.players[*name].capital.unwrap())
+            .collect();
+        assert_ne!(capitals[0], capitals[1]);
+        for (name, capital) in [&alice, &bob].iter().zip(capitals.iter()) {
+            assert_eq!(game.data.players[*name].lands, vec![*capital]);
+            assert_eq!(game.data.world[capital].players, vec![name.to_string()]);
+        }
+    }
+
+    #[test]
+    fn test_starting_buildings() {
+        let mut game = game();
+        let (alice, bob) = ("alice".to_string(), "bob".to_string());
+        game.add_player(alice.clone()).unwrap();
+        game.add_player(bob.clone()).unwrap();
+        let (alice, bob) = (&game.data.players[&alice], &game.data.players[&bob]);
+        let (home, away) = (bob.capital.unwrap(), alice.capital.unwrap());
+        //the lumberyard and the iron mine
+        assert_eq!(bob.buildings_on(&home), 2);
+        assert_eq!(bob.buildings_on(&away), 0);
+        assert_eq!(alice.buildings_on(&home), 0);
+    }
+
+    #[test]
+    fn test_claim() {
+        let mut game = game();
+        let name = "alice".to_string();
+        let capital = game.add_player(name.clone()).unwrap().capital.unwrap();
+        let player = game.data.players.get_mut(&name).unwrap();
+        player.deposit(ResourceID(0), 30).unwrap();
+
+        //only tiles bordering the lands of the player can be claimed
+        let far = Position { x: 5, y: 5 };
+        let reply = game.process(&name, build(far, 1));
+        assert!(matches!(reply, Err(Exception::Unreachable(pos)) if pos == far));
+        for edge in [i32::MIN, i32::MAX].iter() {
+            let edge = Position { x: *edge, y: *edge };
+            let reply = game.process(&name, build(edge, 1));
+            assert!(matches!(reply, Err(Exception::Unreachable(pos)) if pos == edge));
+            assert!(!game.data.world.contains_key(&edge));
         }
+
+        //a house costs 25 wood and claiming its tile 20: 30 wood aren't enough for both
+        let next = Position {
+            x: capital.x,
+            y: capital.y + 1,
+        };
+        assert!(game.process(&name, build(next, 1)).is_err());
+        assert_eq!(wood(&game, &name), 30);
+        assert_eq!(game.data.players[&name].buildings_on(&next), 0);
+        assert_eq!(game.data.players[&name].lands, vec![capital]);
+        assert!(game.get_tile(next).players.is_empty());
+
+        //the claim is only paid when the tile joins the lands
+        let player = game.data.players.get_mut(&name).unwrap();
+        player.deposit(ResourceID(0), 20).unwrap();
+        assert!(game.process(&name, build(next, 1)).is_ok());
+        assert_eq!(wood(&game, &name), 30);
+        assert_eq!(game.data.players[&name].lands, vec![capital, next]);
+        assert_eq!(game.get_tile(next).players, vec![name.clone()]);
+        assert!(game.process(&name, build(next, 1)).is_ok());
+        assert_eq!(wood(&game, &name), 30);
+        assert_eq!(game.data.players[&name].buildings_on(&next), 2);
+
+        //lands grow from the tiles claimed so far
+        let further = Position {
+            x: next.x,
+            y: next.y + 1,
+        };
+        game.data
+            .players
+            .get_mut(&name)
+            .unwrap()
+            .deposit(ResourceID(0), 20)
+            .unwrap();
+        assert!(game.process(&name, build(further, 1)).is_ok());
+        assert_eq!(wood(&game, &name), 30);
+    }
+
+    #[test]
+    fn test_vacate_stranger() {
+        let mut game = game();
+        let (alice, bob) = ("alice".to_string(), "bob".to_string());
+        for name in [&alice, &bob].iter() {
+            let player = game.add_player(name.to_string()).unwrap();
+            player.deposit(ResourceID(0), 90).unwrap();
+        }
+        let home = game.data.players[&alice].capital.unwrap();
+        let away = game.data.players[&bob].capital.unwrap();
+        let topology = game.data.pos_gen.topology();
+        let shared = topology
+            .neighbours(&home)
+            .into_iter()
+            .find(|pos| topology.adjacent(pos, &away))
+            .unwrap();
+        for name in [&alice, &bob].iter() {
+            assert!(game.process(name, build(shared, 1)).is_ok());
+        }
+
+        //bob never lived on the capital of alice, leaving it doesn't cost them their contact
+        game.vacate(&bob, home);
+        assert_eq!(game.data.players[&alice].contacts().get(&bob), Some(&1));
+        assert_eq!(game.data.players[&bob].contacts().get(&alice), Some(&1));
+        assert_eq!(game.data.world[&home].players, vec![alice.clone()]);
+    }
+
+    #[test]
+    fn test_contacts() {
+        let mut game = game();
+        let (alice, bob) = ("alice".to_string(), "bob".to_string());
+        for name in [&alice, &bob].iter() {
+            let player = game.add_player(name.to_string()).unwrap();
+            player.deposit(ResourceID(0), 90).unwrap();
+        }
+        let capital = |game: &Game, name: &Username| game.data.players[name].capital.unwrap();
+        let (home, away) = (capital(&game, &alice), capital(&game, &bob));
+        let topology = game.data.pos_gen.topology();
+        let shared: Vec<Position> = topology
+            .neighbours(&home)
+            .into_iter()
+            .filter(|pos| topology.adjacent(pos, &away))
+            .take(2)
+            .collect();
+        assert_eq!(shared.len(), 2);
+        let contacts = |game: &Game| game.data.players[&alice].contacts().get(&bob).copied();
+        assert_eq!(contacts(&game), None);
+
+        //players are in contact through each tile they share
+        for pos in shared.iter() {
+            for name in [&alice, &bob].iter() {
+                assert!(game.process(name, build(*pos, 1)).is_ok());
+            }
+        }
+        assert_eq!(contacts(&game), Some(2));
+        assert_eq!(game.data.players[&bob].contacts().get(&alice), Some(&2));
+
+        let demolish = |pos| {
+            Event::Player(Action::Demolish {
+                pos,
+                building: BuildingID(3),
+                amount: 1,
+            })
+        };
+        assert!(game.process(&alice, demolish(shared[0])).is_ok());
+        assert_eq!(contacts(&game), Some(1));
+        assert_eq!(game.data.world[&shared[0]].players, vec![bob.clone()]);
+
+        //tiles which were never generated can't be demolished on, nor are they generated
+        let nowhere = Position { x: 1000, y: 1000 };
+        assert!(matches!(
+            game.process(&alice, demolish(nowhere)),
+            Err(Exception::TileNotOwned(pos)) if pos == nowhere
+        ));
+        assert!(!game.data.world.contains_key(&nowhere));
+
+        //without a shared tile they lose contact and can't trade anymore
+        assert!(game.process(&alice, demolish(shared[1])).is_ok());
+        assert_eq!(contacts(&game), None);
+        assert!(!game.data.players[&bob].in_contact(&alice));
+        let entry = |amount| ResourceEntry {
+            id: ResourceID(0),
+            amount,
+        };
+        let trade = Event::Player(Action::Trade {
+            from: alice.clone(),
+            to: bob.clone(),
+            offer: Offer {
+                offering: vec![entry(10)],
+                requesting: vec![entry(10)],
+            },
+        });
+        assert!(matches!(
+            game.process(&alice, trade),
+            Err(Exception::NoContact(name)) if name == bob
+        ));
     }
 }
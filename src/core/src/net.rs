@@ -0,0 +1,459 @@
+//! Reliable and ordered delivery of messages over UDP.
+//!
+//! UDP may lose, duplicate or reorder datagrams and can't carry much in one of them. A `Channel` sits at each
+//! end of a link and fixes this: messages are split into fragments which the other end acknowledges, those which
+//! aren't are sent again after a timeout that doubles each time. Received fragments are put back together and
+//! messages are handed over once, in the order they were sent.
+//!
+//! Channels don't do any IO: datagrams read from the socket are given to `receive` and those to write
+//! are taken from `datagrams`. `tick` must be called regularly for lost fragments to be sent again.
+//!
+//! A datagram is made of a header of big endian integers followed by the payload, if any:
+//! - a fragment: `0u8`, stream `u32`, message `u32`, index `u16`, count `u16`, payload
+//! - an acknowledgement: `1u8`, stream `u32`, message `u32`, index `u16`
+//!
+//! The stream identifies the sender's channel. A peer that comes back with a new channel starts a new stream,
+//! which resets what the other end expects from it. Only the first message of a stream can start it, and streams
+//! which were replaced are ignored, so that datagrams delayed from an older stream can't reset the channel.
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+/// Largest payload carried by a datagram, bigger messages are split.
+///
+/// This keeps datagrams below the usual MTU, fragmented IP packets are lost more often.
+pub const MAX_FRAGMENT: usize = 1024;
+/// Largest datagram sent by a channel.
+pub const MAX_DATAGRAM: usize = FRAGMENT_HEADER + MAX_FRAGMENT;
+/// Largest number of fragments of a message, which makes for messages of up to 1 MiB.
+pub const MAX_FRAGMENTS: usize = 1024;
+/// Largest message, of `MAX_FRAGMENTS` fragments. Other transports accept messages of the same size.
+pub const MAX_MESSAGE: usize = MAX_FRAGMENT * MAX_FRAGMENTS;
+/// Time waited for an acknowledgement before a fragment is first sent again.
+pub const RETRY_TIMEOUT: Duration = Duration::from_millis(200);
+/// Longest time waited between two sends of a fragment.
+pub const MAX_RETRY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of sends of a fragment after which the peer is deemed unreachable.
+pub const MAX_TRIES: u32 = 10;
+//messages received this far ahead of the next one to deliver are dropped
+const WINDOW: u32 = 1024;
+//bytes of messages which wait for earlier ones beyond which their fragments are dropped, to be sent again later.
+//This bounds the memory used by a peer to this and a message of `MAX_FRAGMENTS`, the next one to hand over.
+const MAX_BUFFERED: usize = 4 * MAX_MESSAGE;
+//number of replaced streams of the peer which are remembered
+const RETIRED: usize = 8;
+
+const FRAGMENT: u8 = 0;
+const ACK: u8 = 1;
+const FRAGMENT_HEADER: usize = 13;
+const ACK_LEN: usize = 11;
+
+/// What went wrong on a channel.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetError {
+    /// The datagram doesn't follow the layout of the module.
+    Malformed,
+    /// The message is bigger than `MAX_FRAGMENTS` fragments.
+    TooLarge(usize),
+    /// A fragment was sent `MAX_TRIES` times without being acknowledged.
+    Unreachable,
+}
+
+//A fragment sent to the peer which wasn't acknowledged yet.
+#[derive(Debug)]
+struct Unacked {
+    datagram: Vec<u8>,
+    //when it must be sent again
+    deadline: Instant,
+    timeout: Duration,
+    tries: u32,
+}
+
+/// One end of a link with a peer, see the module documentation.
+#[derive(Debug)]
+pub struct Channel {
+    stream: u32,
+    //id of the next message sent
+    next: u32,
+    unacked: BTreeMap<(u32, u16), Unacked>,
+    //stream of the peer, unknown until it sends something
+    peer: Option<u32>,
+    //previous streams of the peer, most recent last
+    retired: Vec<u32>,
+    //id of the next message to hand over
+    expected: u32,
+    //fragments of the messages which are still incomplete
+    partial: HashMap<u32, Vec<Option<Vec<u8>>>>,
+    //complete messages waiting for the previous ones
+    complete: BTreeMap<u32, Vec<u8>>,
+    //bytes held by `partial` and `complete`
+    buffered: usize,
+    outgoing: Vec<Vec<u8>>,
+    last_heard: Option<Instant>,
+}
+
+impl Channel {
+    pub fn new() -> Channel {
+        //a random stream, so that a peer can tell a restarted channel from the previous one
+        let stream = RandomState::new().build_hasher().finish() as u32;
+        Channel {
+            stream,
+            next: 0,
+            unacked: BTreeMap::new(),
+            peer: None,
+            retired: Vec::new(),
+            expected: 0,
+            partial: HashMap::new(),
+            complete: BTreeMap::new(),
+            buffered: 0,
+            outgoing: Vec::new(),
+            last_heard: None,
+        }
+    }
+
+    /// Splits `message` into fragments, to be sent by the next call to `datagrams`.
+    pub fn send(&mut self, message: &[u8], now: Instant) -> Result<(), NetError> {
+        //even empty messages take a fragment
+        let count = message.chunks(MAX_FRAGMENT).len().max(1);
+        if count > MAX_FRAGMENTS {
+            return Err(NetError::TooLarge(message.len()));
+        }
+        let id = self.next;
+        self.next = self.next.wrapping_add(1);
+        for index in 0..count {
+            let start = index * MAX_FRAGMENT;
+            let payload = &message[start..message.len().min(start + MAX_FRAGMENT)];
+            let mut datagram = Vec::with_capacity(FRAGMENT_HEADER + payload.len());
+            datagram.push(FRAGMENT);
+            datagram.extend_from_slice(&self.stream.to_be_bytes());
+            datagram.extend_from_slice(&id.to_be_bytes());
+            datagram.extend_from_slice(&(index as u16).to_be_bytes());
+            datagram.extend_from_slice(&(count as u16).to_be_bytes());
+            datagram.extend_from_slice(payload);
+            self.outgoing.push(datagram.clone());
+            self.unacked.insert(
+                (id, index as u16),
+                Unacked {
+                    datagram,
+                    deadline: now + RETRY_TIMEOUT,
+                    timeout: RETRY_TIMEOUT,
+                    tries: 1,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Reads a datagram of the peer. Returns the messages that can now be handed over, in order.
+    pub fn receive(&mut self, datagram: &[u8], now: Instant) -> Result<Vec<Vec<u8>>, NetError> {
+        let mut reader = Reader(datagram);
+        let kind = reader.u8()?;
+        let stream = reader.u32()?;
+        let id = reader.u32()?;
+        let index = reader.u16()?;
+        match kind {
+            ACK if reader.0.is_empty() => {
+                if stream == self.stream {
+                    self.unacked.remove(&(id, index));
+                }
+                self.last_heard = Some(now);
+                Ok(Vec::new())
+            }
+            FRAGMENT => {
+                let count = reader.u16()? as usize;
+                if index as usize >= count || count > MAX_FRAGMENTS {
+                    return Err(NetError::Malformed);
+                }
+                self.last_heard = Some(now);
+                if self.peer != Some(stream) {
+                    if id != 0 || self.retired.contains(&stream) {
+                        return Ok(Vec::new());
+                    }
+                    self.reset(stream);
+                }
+                //messages already handed over are acknowledged again as the previous ack may have been lost,
+                //those too far ahead are dropped and will be sent again
+                if id.wrapping_sub(self.expected) >= WINDOW {
+                    if self.expected.wrapping_sub(id) <= WINDOW {
+                        self.ack(stream, id, index);
+                    }
+                    return Ok(Vec::new());
+                }
+                let payload = reader.0;
+                if id != self.expected && self.buffered + payload.len() > MAX_BUFFERED {
+                    return Ok(Vec::new());
+                }
+                self.ack(stream, id, index);
+                if !self.complete.contains_key(&id) {
+                    let fragments = self.partial.entry(id).or_insert_with(|| vec![None; count]);
+                    if fragments.len() != count {
+                        return Err(NetError::Malformed);
+                    }
+                    let fragment = &mut fragments[index as usize];
+                    if fragment.is_none() {
+                        self.buffered += payload.len();
+                        *fragment = Some(payload.to_vec());
+                    }
+                    if fragments.iter().all(Option::is_some) {
+                        let fragments = self.partial.remove(&id).unwrap();
+                        self.complete
+                            .insert(id, fragments.into_iter().flatten().flatten().collect());
+                    }
+                }
+                let mut messages = Vec::new();
+                while let Some(message) = self.complete.remove(&self.expected) {
+                    self.buffered -= message.len();
+                    messages.push(message);
+                    self.expected = self.expected.wrapping_add(1);
+                }
+                Ok(messages)
+            }
+            _ => Err(NetError::Malformed),
+        }
+    }
+
+    /// Queues the fragments whose acknowledgement is late to be sent again.
+    ///
+    /// # Errors
+    /// Fails with `NetError::Unreachable` once a fragment was sent `MAX_TRIES` times. The channel should then be dropped.
+    pub fn tick(&mut self, now: Instant) -> Result<(), NetError> {
+        for unacked in self.unacked.values_mut() {
+            if unacked.deadline > now {
+                continue;
+            }
+            if unacked.tries >= MAX_TRIES {
+                return Err(NetError::Unreachable);
+            }
+            unacked.tries += 1;
+            unacked.timeout = (unacked.timeout * 2).min(MAX_RETRY_TIMEOUT);
+            unacked.deadline = now + unacked.timeout;
+            self.outgoing.push(unacked.datagram.clone());
+        }
+        Ok(())
+    }
+
+    /// The datagrams to write to the socket, in order.
+    pub fn datagrams(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// True if everything sent was acknowledged.
+    pub fn is_idle(&self) -> bool {
+        self.unacked.is_empty()
+    }
+
+    /// When the peer last sent a datagram, if it ever did.
+    pub fn last_heard(&self) -> Option<Instant> {
+        self.last_heard
+    }
+
+    //The peer started a new stream, what it sent before will never be completed.
+    fn reset(&mut self, stream: u32) {
+        if let Some(previous) = self.peer.replace(stream) {
+            if self.retired.len() == RETIRED {
+                self.retired.remove(0);
+            }
+            self.retired.push(previous);
+        }
+        self.expected = 0;
+        self.partial.clear();
+        self.complete.clear();
+        self.buffered = 0;
+    }
+
+    fn ack(&mut self, stream: u32, id: u32, index: u16) {
+        let mut datagram = Vec::with_capacity(ACK_LEN);
+        datagram.push(ACK);
+        datagram.extend_from_slice(&stream.to_be_bytes());
+        datagram.extend_from_slice(&id.to_be_bytes());
+        datagram.extend_from_slice(&index.to_be_bytes());
+        self.outgoing.push(datagram);
+    }
+}
+
+impl Default for Channel {
+    fn default() -> Channel {
+        Channel::new()
+    }
+}
+
+//Reads the big endian integers of a header, failing if the datagram is too short.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], NetError> {
+        if self.0.len() < len {
+            return Err(NetError::Malformed);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, NetError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, NetError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, NetError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::Malformed => write!(f, "the datagram is malformed"),
+            NetError::TooLarge(len) => write!(f, "a message of {} bytes is too large", len),
+            NetError::Unreachable => write!(f, "the peer stopped acknowledging messages"),
+        }
+    }
+}
+
+impl Error for NetError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Carries the datagrams of `from` to `to`, dropping those whose number is a multiple of `drop`,
+    //duplicating the others and delivering them in reverse order.
+    fn carry(
+        from: &mut Channel,
+        to: &mut Channel,
+        now: Instant,
+        drop: usize,
+        count: &mut usize,
+    ) -> Vec<Vec<u8>> {
+        let mut datagrams = from.datagrams();
+        datagrams.reverse();
+        let mut messages = Vec::new();
+        for datagram in datagrams {
+            *count += 1;
+            if (*count).is_multiple_of(drop) {
+                continue;
+            }
+            messages.extend(to.receive(&datagram, now).unwrap());
+            messages.extend(to.receive(&datagram, now).unwrap());
+        }
+        messages
+    }
+
+    #[test]
+    fn test_lossy_link() {
+        let (mut client, mut server) = (Channel::new(), Channel::new());
+        let mut now = Instant::now();
+        let big: Vec<u8> = (0..MAX_FRAGMENT * 3 + 10).map(|i| i as u8).collect();
+        let sent = vec![b"build".to_vec(), big, Vec::new(), b"hire".to_vec()];
+        for message in sent.iter() {
+            client.send(message, now).unwrap();
+        }
+
+        let mut received = Vec::new();
+        let mut count = 0;
+        for _ in 0..20 {
+            received.extend(carry(&mut client, &mut server, now, 3, &mut count));
+            carry(&mut server, &mut client, now, 4, &mut count);
+            now += MAX_RETRY_TIMEOUT;
+            client.tick(now).unwrap();
+        }
+        assert_eq!(received, sent);
+        assert!(client.is_idle());
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let mut channel = Channel::new();
+        let mut now = Instant::now();
+        channel.send(b"hello", now).unwrap();
+        let mut sends = channel.datagrams().len();
+        let result = loop {
+            now += MAX_RETRY_TIMEOUT;
+            if let Err(error) = channel.tick(now) {
+                break error;
+            }
+            sends += channel.datagrams().len();
+        };
+        assert_eq!(result, NetError::Unreachable);
+        assert_eq!(sends, MAX_TRIES as usize);
+        assert_eq!(
+            channel.send(&vec![0; MAX_FRAGMENT * MAX_FRAGMENTS + 1], now),
+            Err(NetError::TooLarge(MAX_FRAGMENT * MAX_FRAGMENTS + 1))
+        );
+    }
+
+    #[test]
+    fn test_new_stream() {
+        let (mut old, mut server) = (Channel::new(), Channel::new());
+        let now = Instant::now();
+        old.send(b"first", now).unwrap();
+        old.send(b"second", now).unwrap();
+        let first = old.datagrams().remove(0);
+        assert_eq!(
+            server.receive(&first, now).unwrap(),
+            vec![b"first".to_vec()]
+        );
+        //the client restarted, its new channel starts over from the first message
+        let mut new = Channel::new();
+        new.send(b"again", now).unwrap();
+        let again = new.datagrams().remove(0);
+        assert_eq!(
+            server.receive(&again, now).unwrap(),
+            vec![b"again".to_vec()]
+        );
+        //datagrams of the old stream which were delayed don't reset the new one
+        for datagram in old.datagrams().iter().chain([first].iter()) {
+            assert!(server.receive(datagram, now).unwrap().is_empty());
+        }
+        new.send(b"next", now).unwrap();
+        let next = new.datagrams().remove(0);
+        assert_eq!(server.receive(&next, now).unwrap(), vec![b"next".to_vec()]);
+        assert_eq!(
+            server.receive(&[FRAGMENT, 0, 0], now),
+            Err(NetError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_buffered() {
+        let (mut client, mut server) = (Channel::new(), Channel::new());
+        let mut now = Instant::now();
+        client.send(b"first", now).unwrap();
+        server.receive(&client.datagrams()[0], now).unwrap();
+        let big = vec![7; MAX_MESSAGE];
+        for _ in 0..6 {
+            client.send(&big, now).unwrap();
+        }
+        //the second message is lost, those after it can't all be held until it is sent again
+        let mut datagrams = client.datagrams();
+        let second: Vec<Vec<u8>> = datagrams.drain(..MAX_FRAGMENTS).collect();
+        for datagram in datagrams {
+            assert!(server.receive(&datagram, now).unwrap().is_empty());
+        }
+        assert!(server.buffered <= MAX_BUFFERED);
+        let mut received = 0;
+        for datagram in second {
+            received += server.receive(&datagram, now).unwrap().len();
+        }
+        assert_eq!(received, 5);
+
+        //what was dropped is sent again
+        let mut count = 0;
+        while !client.is_idle() {
+            carry(&mut server, &mut client, now, usize::MAX, &mut count);
+            now += MAX_RETRY_TIMEOUT;
+            client.tick(now).unwrap();
+            received += carry(&mut client, &mut server, now, usize::MAX, &mut count).len();
+        }
+        assert_eq!(received, 6);
+        assert_eq!(server.buffered, 0);
+    }
+}
@@ -0,0 +1,949 @@
+//! Loading and checking the game data.
+//!
+//! A data pack is a directory holding the JSON files which describe the buildings, the resources and the rules of a game.
+//! Packs are written by hand, so they are checked thoroughly when they are loaded: everything that is referenced
+//! must exist and buildings must make sense. All the problems of a pack are reported at once.
+//!
+//! A game is played with a base pack and any number of overlay packs (mods) stacked on top of it, in order.
+//! Every pack has a `pack.json` manifest giving its `name` and `version`. Buildings and resources are keyed by
+//! identifiers: an overlay adds those it defines, replaces those it redefines and removes those it sets to `null`.
+//! Overlays may also replace the world, research and population rules by providing the file.
+//! Identifiers are turned into IDs once all packs are stacked. Those that are numbers, like in the base pack,
+//! are their own ID while the other ones get the lowest ID left, in the order they first appear: pack after pack
+//! and from the top to the bottom of each file. Saves refer to IDs, so an overlay should add its entries after
+//! the existing ones and never take a number another overlay gave out. References to buildings and resources,
+//! in any file of any pack, use the identifiers.
+use crate::buildings::{AllBuildings, Building, BuildingID};
+use crate::population::PopulationRules;
+use crate::research::ResearchRules;
+use crate::resources::{AllResources, ResourceID};
+use crate::world::WorldRules;
+use serde::de::{DeserializeOwned, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+const MANIFEST_FILE: &str = "pack.json";
+const BUILDINGS_FILE: &str = "buildings.json";
+const RESOURCES_FILE: &str = "resources.json";
+const WORLD_FILE: &str = "world.json";
+const RESEARCH_FILE: &str = "research.json";
+const POPULATION_FILE: &str = "population.json";
+
+//Fields of a building, in the order of the list format.
+const BUILDING_FIELDS: [&str; 8] = [
+    "name",
+    "extractor",
+    "prerequisites",
+    "produced",
+    "consumed",
+    "max_workers",
+    "construction_cost",
+    "storage",
+];
+
+/// Name and version of a pack, from its `pack.json` manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackInfo {
+    pub name: String,
+    pub version: String,
+}
+
+/// Everything a game is played with, read from a directory of JSON files.
+#[derive(Debug)]
+pub struct DataPack {
+    /// The packs that were stacked, the base one first.
+    pub manifest: Vec<PackInfo>,
+    pub buildings: AllBuildings,
+    pub resources: AllResources,
+    pub world: WorldRules,
+    pub research: ResearchRules,
+    pub population: PopulationRules,
+}
+
+/// The part of the data packs clients need to show the game: the buildings and the resources.
+///
+/// Servers send them to clients so that a client can join a game played with packs it doesn't have, see `response::Pack`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Definitions {
+    pub manifest: Vec<PackInfo>,
+    pub buildings: AllBuildings,
+    pub resources: AllResources,
+}
+
+/// Why a data pack couldn't be loaded.
+#[derive(Debug)]
+pub enum PackError {
+    /// A file of the pack couldn't be read.
+    Io { file: PathBuf, error: io::Error },
+    /// A file of the pack isn't JSON or doesn't have the expected layout.
+    Parse {
+        file: PathBuf,
+        error: serde_json::Error,
+    },
+    /// The files were read but their content doesn't add up.
+    Invalid(Vec<Problem>),
+}
+
+/// Inconsistency found in a data pack.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// `referrer` mentions a resource that doesn't exist.
+    UnknownResource {
+        referrer: String,
+        resource: ResourceID,
+    },
+    /// `referrer` mentions a building that doesn't exist.
+    UnknownBuilding {
+        referrer: String,
+        building: BuildingID,
+    },
+    /// These buildings each require the next one, and the last the first. None of them could ever be researched.
+    PrerequisiteCycle(Vec<BuildingID>),
+    /// An extractor that doesn't produce anything.
+    BarrenExtractor(BuildingID),
+    /// `referrer` mentions a resource identifier that no pack defines.
+    UndefinedResource {
+        referrer: String,
+        identifier: String,
+    },
+    /// `referrer` mentions a building identifier that no pack defines.
+    UndefinedBuilding {
+        referrer: String,
+        identifier: String,
+    },
+    /// There are more than 256 resources or buildings, this one couldn't be given an ID.
+    OutOfIDs(String),
+}
+
+impl DataPack {
+    /// Reads and checks the data pack of the `dir` directory, without overlays.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<DataPack, PackError> {
+        DataPack::layered(dir, &[] as &[&Path])
+    }
+
+    /// Reads the `base` pack, stacks the `overlays` on it in order and checks the result.
+    ///
+    /// The base pack must have all the files, overlays only those they change.
+    pub fn layered<P: AsRef<Path>, Q: AsRef<Path>>(
+        base: P,
+        overlays: &[Q],
+    ) -> Result<DataPack, PackError> {
+        let mut layers = Layers::default();
+        layers.stack(base.as_ref(), true)?;
+        for overlay in overlays {
+            layers.stack(overlay.as_ref(), false)?;
+        }
+        let pack = layers.resolve()?;
+        let problems = pack.check();
+        if problems.is_empty() {
+            Ok(pack)
+        } else {
+            Err(PackError::Invalid(problems))
+        }
+    }
+
+    /// Every inconsistency of the pack. Problems are sorted by building, then by rule file.
+    pub fn check(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let mut ids: Vec<&BuildingID> = self.buildings.keys().collect();
+        ids.sort();
+        for id in ids.iter() {
+            let building = &self.buildings[id];
+            let referrer = format!("building {} ({})", id.0, building.name);
+            for amounts in [
+                &building.produced,
+                &building.consumed,
+                &building.construction_cost,
+                &building.storage,
+            ]
+            .iter()
+            {
+                self.check_resources(&referrer, amounts.keys(), &mut problems);
+            }
+            self.check_buildings(&referrer, building.prerequisites.iter(), &mut problems);
+            if building.extractor && building.produced.is_empty() {
+                problems.push(Problem::BarrenExtractor(**id));
+            }
+        }
+        problems.extend(
+            self.prerequisite_cycles()
+                .into_iter()
+                .map(Problem::PrerequisiteCycle),
+        );
+
+        for biome in self.world.biomes.iter() {
+            let referrer = format!("biome {}", biome.name);
+            for patch in biome.patches.iter() {
+                self.check_resources(&referrer, Some(&patch.resource), &mut problems);
+                self.check_buildings(&referrer, Some(&patch.building), &mut problems);
+            }
+        }
+        let world = "world rules".to_string();
+        self.check_resources(&world, self.world.renewable.keys(), &mut problems);
+        self.check_resources(&world, self.world.claim_cost.keys(), &mut problems);
+        let research = "research rules".to_string();
+        self.check_resources(&research, self.research.base_cost.keys(), &mut problems);
+        let population = "population rules".to_string();
+        self.check_resources(&population, self.population.food.keys(), &mut problems);
+        self.check_buildings(&population, self.population.housing.keys(), &mut problems);
+        problems
+    }
+
+    fn check_resources<'a, I: IntoIterator<Item = &'a ResourceID>>(
+        &self,
+        referrer: &str,
+        resources: I,
+        problems: &mut Vec<Problem>,
+    ) {
+        let mut unknown: Vec<&ResourceID> = resources
+            .into_iter()
+            .filter(|id| !self.resources.contains_key(id))
+            .collect();
+        unknown.sort();
+        problems.extend(
+            unknown
+                .into_iter()
+                .map(|resource| Problem::UnknownResource {
+                    referrer: referrer.to_string(),
+                    resource: *resource,
+                }),
+        );
+    }
+
+    fn check_buildings<'a, I: IntoIterator<Item = &'a BuildingID>>(
+        &self,
+        referrer: &str,
+        buildings: I,
+        problems: &mut Vec<Problem>,
+    ) {
+        let mut unknown: Vec<&BuildingID> = buildings
+            .into_iter()
+            .filter(|id| !self.buildings.contains_key(id))
+            .collect();
+        unknown.sort();
+        problems.extend(
+            unknown
+                .into_iter()
+                .map(|building| Problem::UnknownBuilding {
+                    referrer: referrer.to_string(),
+                    building: *building,
+                }),
+        );
+    }
+
+    //Depth-first walk of the prerequisites. Meeting a building that is still being walked closes a cycle.
+    fn prerequisite_cycles(&self) -> Vec<Vec<BuildingID>> {
+        fn walk(
+            id: BuildingID,
+            buildings: &AllBuildings,
+            done: &mut HashMap<BuildingID, bool>,
+            path: &mut Vec<BuildingID>,
+            cycles: &mut Vec<Vec<BuildingID>>,
+        ) {
+            done.insert(id, false);
+            path.push(id);
+            let mut prerequisites = buildings[&id].prerequisites.clone();
+            prerequisites.sort();
+            for next in prerequisites {
+                match done.get(&next) {
+                    _ if !buildings.contains_key(&next) => (),
+                    None => walk(next, buildings, done, path, cycles),
+                    Some(false) => {
+                        let start = path.iter().position(|crt| *crt == next).unwrap();
+                        cycles.push(path[start..].to_vec());
+                    }
+                    Some(true) => (),
+                }
+            }
+            path.pop();
+            done.insert(id, true);
+        }
+
+        let mut ids: Vec<BuildingID> = self.buildings.keys().copied().collect();
+        ids.sort();
+        let mut done = HashMap::new();
+        let mut cycles = Vec::new();
+        for id in ids {
+            if !done.contains_key(&id) {
+                walk(id, &self.buildings, &mut done, &mut Vec::new(), &mut cycles);
+            }
+        }
+        cycles
+    }
+}
+
+impl Definitions {
+    /// A fingerprint of the definitions which is the same on every machine.
+    ///
+    /// This is the 64 bits FNV-1a hash of the definitions written as JSON, with the entries of maps sorted.
+    pub fn hash(&self) -> u64 {
+        //the objects of JSON values are sorted by key
+        let json = serde_json::to_value(self)
+            .expect("definitions can always be written as JSON")
+            .to_string();
+        json.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    /// The definitions whose hash is `hash` if they were cached in the `dir` directory, see `cache`.
+    pub fn cached<P: AsRef<Path>>(dir: P, hash: u64) -> Option<Definitions> {
+        let content = std::fs::read(dir.as_ref().join(cache_name(hash))).ok()?;
+        let definitions: Definitions = serde_json::from_slice(&content).ok()?;
+        //a file which was tampered with is as good as a missing one
+        Some(definitions).filter(|definitions| definitions.hash() == hash)
+    }
+
+    /// Writes the definitions to the `dir` directory so that they can be found by `cached`. Returns the path of the file.
+    pub fn cache<P: AsRef<Path>>(&self, dir: P) -> io::Result<PathBuf> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(cache_name(self.hash()));
+        std::fs::write(&path, serde_json::to_vec(self)?)?;
+        Ok(path)
+    }
+}
+
+fn cache_name(hash: u64) -> String {
+    format!("{:016x}.json", hash)
+}
+
+//A file of a pack along with its path, to report errors.
+type Layer<T> = (T, PathBuf);
+
+//The packs stacked so far, whose identifiers are yet to be turned into IDs.
+#[derive(Default)]
+struct Layers {
+    manifest: Vec<PackInfo>,
+    //by order of appearance
+    resources: Vec<(String, Layer<String>)>,
+    buildings: Vec<(String, Layer<Value>)>,
+    world: Option<Layer<Value>>,
+    research: Option<Layer<Value>>,
+    population: Option<Layer<Value>>,
+}
+
+impl Layers {
+    //Stacks the pack of the `dir` directory on the previous ones.
+    fn stack(&mut self, dir: &Path, base: bool) -> Result<(), PackError> {
+        self.manifest
+            .push(read(dir, MANIFEST_FILE, true)?.unwrap().0);
+        if let Some((entries, file)) = read(dir, RESOURCES_FILE, base)? {
+            overlay(&mut self.resources, entries, &file);
+        }
+        if let Some((entries, file)) = read(dir, BUILDINGS_FILE, base)? {
+            overlay(&mut self.buildings, entries, &file);
+        }
+        for (rules, name) in [
+            (&mut self.world, WORLD_FILE),
+            (&mut self.research, RESEARCH_FILE),
+            (&mut self.population, POPULATION_FILE),
+        ]
+        .iter_mut()
+        {
+            if let Some(layer) = read(dir, name, base)? {
+                **rules = Some(layer);
+            }
+        }
+        Ok(())
+    }
+
+    //Gives IDs to the identifiers and builds the pack.
+    fn resolve(self) -> Result<DataPack, PackError> {
+        let mut problems = Vec::new();
+        let resources = Ids::new(
+            &self.resources,
+            |referrer, identifier| Problem::UndefinedResource {
+                referrer,
+                identifier,
+            },
+            &mut problems,
+        );
+        let buildings = Ids::new(
+            &self.buildings,
+            |referrer, identifier| Problem::UndefinedBuilding {
+                referrer,
+                identifier,
+            },
+            &mut problems,
+        );
+
+        let mut all = Vec::new();
+        for (identifier, (mut building, file)) in self.buildings {
+            let referrer = format!("building {} of {}", identifier, file.display());
+            buildings.list(
+                field(&mut building, "prerequisites"),
+                &referrer,
+                &mut problems,
+            );
+            for name in ["produced", "consumed", "construction_cost", "storage"].iter() {
+                resources.keys(field(&mut building, name), &referrer, &mut problems);
+            }
+            all.push((identifier, (building, file)));
+        }
+
+        //the base pack has all the files
+        let (mut world, world_file) = self.world.unwrap();
+        let referrer = world_file.display().to_string();
+        resources.keys(world.get_mut("renewable"), &referrer, &mut problems);
+        resources.keys(world.get_mut("claim_cost"), &referrer, &mut problems);
+        if let Some(Value::Array(biomes)) = world.get_mut("biomes") {
+            for biome in biomes {
+                let name = biome.get("name").and_then(Value::as_str).unwrap_or("");
+                let referrer = format!("biome {} of {}", name, world_file.display());
+                if let Some(Value::Array(patches)) = biome.get_mut("patches") {
+                    for patch in patches {
+                        buildings.value(patch.get_mut("building"), &referrer, &mut problems);
+                        resources.value(patch.get_mut("resource"), &referrer, &mut problems);
+                    }
+                }
+            }
+        }
+        let (mut research, research_file) = self.research.unwrap();
+        let referrer = research_file.display().to_string();
+        resources.keys(research.get_mut("base_cost"), &referrer, &mut problems);
+        let (mut population, population_file) = self.population.unwrap();
+        let referrer = population_file.display().to_string();
+        resources.keys(population.get_mut("food"), &referrer, &mut problems);
+        buildings.keys(population.get_mut("housing"), &referrer, &mut problems);
+        if !problems.is_empty() {
+            return Err(PackError::Invalid(problems));
+        }
+
+        let mut all_buildings = HashMap::new();
+        for (identifier, building) in all {
+            let building: Building = parse(building)?;
+            all_buildings.insert(BuildingID(buildings.ids[&identifier]), building);
+        }
+        Ok(DataPack {
+            manifest: self.manifest,
+            buildings: all_buildings,
+            resources: self
+                .resources
+                .into_iter()
+                .map(|(identifier, (name, _))| (ResourceID(resources.ids[&identifier]), name))
+                .collect(),
+            world: parse((world, world_file))?,
+            research: parse((research, research_file))?,
+            population: parse((population, population_file))?,
+        })
+    }
+}
+
+//The IDs given to the identifiers of either resources or buildings.
+struct Ids {
+    ids: HashMap<String, u8>,
+    //the problem of a reference to an identifier that isn't defined
+    undefined: fn(String, String) -> Problem,
+}
+
+impl Ids {
+    fn new<T>(
+        entries: &[(String, Layer<T>)],
+        undefined: fn(String, String) -> Problem,
+        problems: &mut Vec<Problem>,
+    ) -> Ids {
+        let mut ids = HashMap::new();
+        let mut taken = [false; 256];
+        for (identifier, _) in entries {
+            match identifier.parse::<u8>() {
+                Ok(id) if id.to_string() == *identifier => {
+                    ids.insert(identifier.clone(), id);
+                    taken[id as usize] = true;
+                }
+                _ => (),
+            }
+        }
+        let mut free = (0..=255).filter(|id: &u8| !taken[*id as usize]);
+        for (identifier, _) in entries {
+            if ids.contains_key(identifier) {
+                continue;
+            }
+            match free.next() {
+                Some(id) => {
+                    ids.insert(identifier.clone(), id);
+                }
+                None => problems.push(Problem::OutOfIDs(identifier.clone())),
+            }
+        }
+        Ids { ids, undefined }
+    }
+
+    //The ID of the identifier held by `value`, which is either a string or a number.
+    fn id(&self, value: &Value, referrer: &str, problems: &mut Vec<Problem>) -> Option<u8> {
+        let identifier = match value {
+            Value::String(identifier) => identifier.clone(),
+            other => other.to_string(),
+        };
+        let id = self.ids.get(&identifier).copied();
+        if id.is_none() {
+            problems.push((self.undefined)(referrer.to_string(), identifier));
+        }
+        id
+    }
+
+    //Replaces the identifier held by `value` by its ID.
+    fn value(&self, value: Option<&mut Value>, referrer: &str, problems: &mut Vec<Problem>) {
+        if let Some(value) = value {
+            if let Some(id) = self.id(value, referrer, problems) {
+                *value = id.into();
+            }
+        }
+    }
+
+    //Replaces the identifiers of the `list` array by their IDs.
+    fn list(&self, list: Option<&mut Value>, referrer: &str, problems: &mut Vec<Problem>) {
+        if let Some(Value::Array(items)) = list {
+            for item in items {
+                self.value(Some(item), referrer, problems);
+            }
+        }
+    }
+
+    //Replaces the identifiers used as keys of the `map` object by their IDs.
+    fn keys(&self, map: Option<&mut Value>, referrer: &str, problems: &mut Vec<Problem>) {
+        if let Some(Value::Object(entries)) = map {
+            for (key, value) in std::mem::take(entries) {
+                let key = match self.id(&Value::String(key.clone()), referrer, problems) {
+                    Some(id) => id.to_string(),
+                    None => key,
+                };
+                entries.insert(key, value);
+            }
+        }
+    }
+}
+
+//The `name` field of a building, in either format.
+fn field<'a>(building: &'a mut Value, name: &str) -> Option<&'a mut Value> {
+    match building {
+        Value::Array(fields) => BUILDING_FIELDS
+            .iter()
+            .position(|field| *field == name)
+            .and_then(move |idx| fields.get_mut(idx)),
+        Value::Object(fields) => fields.get_mut(name),
+        _ => None,
+    }
+}
+
+//Turns the content of a file whose identifiers were resolved into what it describes.
+fn parse<T: DeserializeOwned>((value, file): Layer<Value>) -> Result<T, PackError> {
+    serde_json::from_value(value).map_err(|error| PackError::Parse { file, error })
+}
+
+//The entries of a buildings or resources file, in the order they are written in as it decides their IDs.
+struct Entries<T>(Vec<(String, Option<T>)>);
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Entries<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Entries<T>, D::Error> {
+        deserializer.deserialize_map(EntriesVisitor(PhantomData))
+    }
+}
+
+struct EntriesVisitor<T>(PhantomData<T>);
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for EntriesVisitor<T> {
+    type Value = Entries<T>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an object of entries keyed by identifier")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let mut entries = Vec::new();
+        while let Some(entry) = access.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Entries(entries))
+    }
+}
+
+//Applies the `entries` of a pack file to those of the previous packs.
+fn overlay<T>(layers: &mut Vec<(String, Layer<T>)>, Entries(entries): Entries<T>, file: &Path) {
+    for (identifier, entry) in entries {
+        let idx = layers.iter().position(|(crt, _)| *crt == identifier);
+        match (idx, entry) {
+            (Some(idx), Some(entry)) => layers[idx].1 = (entry, file.to_path_buf()),
+            (None, Some(entry)) => layers.push((identifier, (entry, file.to_path_buf()))),
+            (Some(idx), None) => {
+                layers.remove(idx);
+            }
+            (None, None) => (),
+        }
+    }
+}
+
+//Reads the `name` file of the `dir` pack. Fails if it doesn't exist and is `required`.
+fn read<T: DeserializeOwned>(
+    dir: &Path,
+    name: &str,
+    required: bool,
+) -> Result<Option<Layer<T>>, PackError> {
+    let file = dir.join(name);
+    let content = match std::fs::read(&file) {
+        Ok(content) => content,
+        Err(error) if error.kind() == io::ErrorKind::NotFound && !required => return Ok(None),
+        Err(error) => return Err(PackError::Io { file, error }),
+    };
+    match serde_json::from_slice(&content) {
+        Ok(content) => Ok(Some((content, file))),
+        Err(error) => Err(PackError::Parse { file, error }),
+    }
+}
+
+impl fmt::Display for PackInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.version)
+    }
+}
+
+impl fmt::Display for PackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PackError::Io { file, error } => {
+                write!(f, "couldn't read {}: {}", file.display(), error)
+            }
+            PackError::Parse { file, error } => {
+                write!(f, "{} is invalid: {}", file.display(), error)
+            }
+            PackError::Invalid(problems) => {
+                write!(f, "the data pack has {} problem(s)", problems.len())?;
+                for problem in problems {
+                    write!(f, "\n- {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for PackError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PackError::Io { error, .. } => Some(error),
+            PackError::Parse { error, .. } => Some(error),
+            PackError::Invalid(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::UnknownResource { referrer, resource } => {
+                write!(
+                    f,
+                    "{} uses resource {} which doesn't exist",
+                    referrer, resource.0
+                )
+            }
+            Problem::UnknownBuilding { referrer, building } => {
+                write!(
+                    f,
+                    "{} uses building {} which doesn't exist",
+                    referrer, building.0
+                )
+            }
+            Problem::PrerequisiteCycle(cycle) => {
+                let ids: Vec<String> = cycle.iter().map(|id| id.0.to_string()).collect();
+                write!(f, "buildings {} require one another", ids.join(" -> "))
+            }
+            Problem::BarrenExtractor(id) => {
+                write!(f, "building {} is an extractor but produces nothing", id.0)
+            }
+            Problem::UndefinedResource {
+                referrer,
+                identifier,
+            } => write!(
+                f,
+                "{} uses resource {} which no pack defines",
+                referrer, identifier
+            ),
+            Problem::UndefinedBuilding {
+                referrer,
+                identifier,
+            } => write!(
+                f,
+                "{} uses building {} which no pack defines",
+                referrer, identifier
+            ),
+            Problem::OutOfIDs(identifier) => {
+                write!(
+                    f,
+                    "{} can't be given an ID, there are already 256 of its kind",
+                    identifier
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{base, dir};
+
+    fn pack(buildings: &str) -> DataPack {
+        DataPack {
+            manifest: Vec::new(),
+            buildings: serde_json::from_str(buildings).unwrap(),
+            resources: serde_json::from_str(r#"{"0": "wood", "1": "iron"}"#).unwrap(),
+            world: serde_json::from_str(
+                r#"{"scale": 8, "low_deposit": 100, "biomes": [{"name": "forest", "noise": [0, 256], "patches": [
+                    {"building": 0, "resource": 0, "chance": 90, "slots": [2, 5], "amount": [300, 800]}
+                ]}]}"#,
+            )
+            .unwrap(),
+            research: serde_json::from_str(r#"{"base_cost": {"0": 20}, "growth": 2, "ticks": 30}"#)
+                .unwrap(),
+            population: serde_json::from_str(
+                r#"{"food": {"1": 1}, "meal": 20, "growth": 1, "starvation": 1, "base_maximum": 10, "housing": {}}"#,
+            )
+            .unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_shipped_pack() {
+        if let Err(error) = DataPack::load(base()) {
+            panic!("{}", error);
+        }
+    }
+
+    #[test]
+    fn test_named_fields() {
+        let tuples = pack(
+            r#"{
+                "0": ["Lumberyard", true, [], {"0": 5}, {}, 3, {"0": 15}],
+                "1": ["Warehouse", false, [0], {}, {}, 0, {"0": 60}, {"0": 150}]
+            }"#,
+        );
+        let named = pack(
+            r#"{
+                "0": {"name": "Lumberyard", "extractor": true, "produced": {"0": 5}, "max_workers": 3, "construction_cost": {"0": 15}},
+                "1": {"name": "Warehouse", "prerequisites": [0], "construction_cost": {"0": 60}, "storage": {"0": 150}}
+            }"#,
+        );
+        for pack in [tuples, named].iter() {
+            assert!(pack.check().is_empty());
+            let warehouse = &pack.buildings[&BuildingID(1)];
+            assert_eq!(warehouse.prerequisites, vec![BuildingID(0)]);
+            assert_eq!(warehouse.storage[&ResourceID(0)], 150);
+            assert!(pack.buildings[&BuildingID(0)].extractor);
+        }
+        //typos in field names are reported instead of silently ignored
+        assert!(serde_json::from_str::<AllBuildings>(
+            r#"{"0": {"name": "Farm", "produce": {"0": 1}}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_problems() {
+        let pack = pack(
+            r#"{
+                "0": ["Lumberyard", true, [2], {"0": 5}, {}, 3, {"4": 15}],
+                "1": ["Mine", true, [7], {}, {}, 3, {}],
+                "2": ["Sawmill", false, [3], {"0": 1}, {"0": 2}, 3, {}],
+                "3": ["Carpenter", false, [0], {}, {}, 3, {}]
+            }"#,
+        );
+        assert_eq!(
+            pack.check(),
+            vec![
+                Problem::UnknownResource {
+                    referrer: "building 0 (Lumberyard)".to_string(),
+                    resource: ResourceID(4)
+                },
+                Problem::UnknownBuilding {
+                    referrer: "building 1 (Mine)".to_string(),
+                    building: BuildingID(7)
+                },
+                Problem::BarrenExtractor(BuildingID(1)),
+                Problem::PrerequisiteCycle(vec![BuildingID(0), BuildingID(2), BuildingID(3)]),
+            ]
+        );
+    }
+
+    //an overlay pack named `name` made of `files`
+    fn overlay(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = dir(name);
+        let manifest = format!(r#"{{"name": "{}", "version": "1.0.0"}}"#, name);
+        std::fs::write(dir.join(MANIFEST_FILE), manifest).unwrap();
+        for (file, content) in files {
+            std::fs::write(dir.join(file), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let dir = dir("errors");
+        let missing = DataPack::load(&dir);
+        for file in std::fs::read_dir(base()).unwrap() {
+            let file = file.unwrap().path();
+            std::fs::copy(&file, dir.join(file.file_name().unwrap())).unwrap();
+        }
+        std::fs::write(dir.join(WORLD_FILE), r#"{"scale": "large"}"#).unwrap();
+        let invalid = DataPack::load(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            matches!(missing, Err(PackError::Io { file, .. }) if file.ends_with(MANIFEST_FILE))
+        );
+        assert!(
+            matches!(invalid, Err(PackError::Parse { file, .. }) if file.ends_with(WORLD_FILE))
+        );
+    }
+
+    #[test]
+    fn test_overlays() {
+        let copper = overlay(
+            "copper",
+            &[
+                (RESOURCES_FILE, r#"{"copper": "copper", "2": "granite"}"#),
+                (
+                    BUILDINGS_FILE,
+                    r#"{
+                        "5": null,
+                        "copper_mine": {"name": "Copper Mine", "extractor": true, "prerequisites": ["1"],
+                            "produced": {"copper": 2}, "max_workers": 4, "construction_cost": {"0": 40}}
+                    }"#,
+                ),
+            ],
+        );
+        let pack = DataPack::layered(base(), &[&copper]);
+        std::fs::remove_dir_all(&copper).unwrap();
+        let pack = pack.unwrap();
+
+        let names: Vec<&str> = pack
+            .manifest
+            .iter()
+            .map(|info| info.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["base", "copper"]);
+        assert_eq!(pack.resources[&ResourceID(2)], "granite");
+        assert_eq!(pack.resources[&ResourceID(4)], "copper");
+        //the Granary was removed, its ID is the lowest one left
+        let mine = &pack.buildings[&BuildingID(5)];
+        assert_eq!(mine.name, "Copper Mine");
+        assert_eq!(mine.prerequisites, vec![BuildingID(1)]);
+        assert_eq!(mine.produced[&ResourceID(4)], 2);
+        assert_eq!(pack.buildings.len(), 6);
+    }
+
+    #[test]
+    fn test_file_order() {
+        let metals = |name: &str, resources: &str| {
+            let dir = overlay(name, &[(RESOURCES_FILE, resources)]);
+            let pack = DataPack::layered(base(), &[&dir]);
+            std::fs::remove_dir_all(&dir).unwrap();
+            pack.unwrap().resources
+        };
+        let before = metals("zinc", r#"{"zinc": "zinc", "copper": "copper"}"#);
+        //adding an entry, even one which sorts first, doesn't change the IDs of the others
+        let after = metals(
+            "aluminium",
+            r#"{"zinc": "zinc", "copper": "copper", "aluminium": "aluminium"}"#,
+        );
+        assert_eq!(before[&ResourceID(4)], "zinc");
+        assert_eq!(before[&ResourceID(5)], "copper");
+        assert_eq!(after[&ResourceID(4)], "zinc");
+        assert_eq!(after[&ResourceID(5)], "copper");
+        assert_eq!(after[&ResourceID(6)], "aluminium");
+    }
+
+    #[test]
+    fn test_undefined() {
+        let famine = overlay("famine", &[(RESOURCES_FILE, r#"{"3": null}"#)]);
+        let pack = DataPack::layered(base(), &[&famine]);
+        std::fs::remove_dir_all(&famine).unwrap();
+        let problems = match pack {
+            Err(PackError::Invalid(problems)) => problems,
+            other => panic!("{:?}", other),
+        };
+        //the farm, the granary and the population rules all use food
+        assert_eq!(problems.len(), 3);
+        assert!(problems.iter().all(|problem| matches!(
+            problem,
+            Problem::UndefinedResource { identifier, .. } if identifier == "3"
+        )));
+    }
+
+    #[test]
+    fn test_manifest_in_save() {
+        let dir = dir("manifest");
+        let mods = overlay("mods", &[]);
+        let game = crate::Game::new(
+            0,
+            0,
+            crate::Topology::Square,
+            DataPack::load(base()).unwrap(),
+        );
+        let path = game.save(&dir, crate::save::Format::Json).unwrap();
+        let modded = crate::Game::load(&path, DataPack::layered(base(), &[&mods]).unwrap());
+        let same = crate::Game::load(&path, DataPack::load(base()).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&mods).unwrap();
+        assert!(modded.is_err());
+        assert!(same.is_ok());
+    }
+
+    #[test]
+    fn test_definitions() {
+        let pack = DataPack::load(base()).unwrap();
+        let definitions = Definitions {
+            manifest: pack.manifest,
+            buildings: pack.buildings,
+            resources: pack.resources,
+        };
+        //the order of maps, which differs between runs, doesn't change the hash
+        let json = serde_json::to_string(&definitions).unwrap();
+        let copy: Definitions = serde_json::from_str(&json).unwrap();
+        assert_eq!(copy.hash(), definitions.hash());
+
+        let dir = dir("definitions");
+        let hash = definitions.hash();
+        let missing = Definitions::cached(&dir, hash);
+        let path = definitions.cache(&dir).unwrap();
+        let cached = Definitions::cached(&dir, hash);
+        let mut modded = definitions.clone();
+        modded.resources.insert(ResourceID(4), "copper".to_string());
+        std::fs::copy(&path, dir.join(cache_name(modded.hash()))).unwrap();
+        let tampered = Definitions::cached(&dir, modded.hash());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(missing, None);
+        assert_eq!(cached, Some(definitions));
+        assert_eq!(tampered, None);
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut game = crate::Game::new(
+            0,
+            0,
+            crate::Topology::Square,
+            DataPack::load(base()).unwrap(),
+        );
+        let name = "alice".to_string();
+        game.add_player(name.clone()).unwrap();
+        game.process(&name, crate::Event::Connected).unwrap();
+        let hash = match game.outbox.pop() {
+            Some((_, crate::Response::Event(crate::Event::Pack(crate::Pack::Hash(hash))))) => hash,
+            other => panic!("{:?}", other),
+        };
+        let reply = game.process(&name, crate::Event::Pack(crate::Pack::GetDefinitions));
+        match reply {
+            Ok(Some(crate::Event::Pack(crate::Pack::Definitions(definitions)))) => {
+                assert_eq!(definitions.hash(), hash);
+                assert_eq!(definitions.buildings.len(), 6);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+}
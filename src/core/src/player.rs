@@ -1,11 +1,15 @@
-use crate::buildings::{AllBuildings, Building, BuildingID, DependencyTree};
+use crate::buildings::{AllBuildings, Building, BuildingID, DependencyGraph};
+use crate::population::{Population, PopulationRules};
+use crate::production::{self, Efficiency, Report};
+use crate::research::{Research, ResearchRules};
 use crate::resources::ResourceID;
-use crate::tile::Position;
+use crate::response::{Event, Exception, World};
 use crate::tile::Tile;
-use crate::trade::{Ledger, Offer, ResourceEntry};
+use crate::tile::{by_position, Position};
+use crate::trade::{tally, Ledger, Offer, ResourceEntry};
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 pub type Username = String;
 
@@ -25,6 +29,16 @@ impl Stockpile {
             maximum: 100,
         }
     }
+
+    /// Amount of the resource held.
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    /// Amount of the resource that can be held.
+    pub fn maximum(&self) -> u32 {
+        self.maximum
+    }
 }
 
 impl Default for Stockpile {
@@ -44,6 +58,7 @@ impl Default for Stockpile {
 pub struct OwnedBuilding {
     total: u32,
     workers: (u32, u32),
+    #[serde(with = "by_position")]
     tiles: HashMap<Position, u32>,
 }
 
@@ -63,28 +78,6 @@ impl Default for OwnedBuilding {
     }
 }
 
-/// Player's subjects
-///
-/// Holds data related to the population of a player's empire.
-/// Most useful for dispatching citizens to jobs as well as managing the total population.
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Population {
-    //here the definition in the repo requires a mapping (ie: HashMap) -> this makes it easier to build upon but less elegant
-    //we respectively have `idle`, `total` and `maximum` -> consider making it an array instead
-    idle: u32,
-    total: u32,
-    maximum: u32,
-}
-
-impl Population {
-    fn new() -> Population {
-        Population {
-            idle: 5,
-            total: 5,
-            maximum: 10,
-        }
-    }
-}
 //ideally this GenMap would be stored in the player struct
 //however it shouldn't be saved in the JSON so it has to be saved elsewhere
 //unless a field can be omitted by serde that is
@@ -109,37 +102,81 @@ pub struct Player {
     contacts: HashMap<Username, u32>,
     //all tiles the player has built on are considered part of its territory. Thus tiles may be owned by more than 1 player.
     pub lands: Vec<Position>,
+    //the tile the player started on. It stays part of its lands even without buildings.
+    #[serde(default)]
+    pub capital: Option<Position>,
+    #[serde(default)]
+    pub research: Research,
+    //the last game tick simulated for the player. It lags behind while the player is offline.
+    #[serde(default)]
+    pub(crate) last_tick: u64,
     trades: Ledger,
     #[serde(skip)]
     gen: Generator,
 }
 
+impl Default for Player {
+    fn default() -> Self {
+        Player::new()
+    }
+}
+
 impl Player {
     ///Creates a new player. Later needs to be registered into GameData.
-    pub fn new(buildings: &AllBuildings) -> Player {
-        let mut p = Player {
+    pub fn new() -> Player {
+        Player {
             buildings: HashMap::new(),
             people: Population::new(),
             resources: HashMap::new(),
             contacts: HashMap::new(),
             lands: Vec::new(),
+            capital: None,
+            research: Research::new(),
+            last_tick: 0,
             trades: Ledger::new(),
             gen: Generator::new(),
-        };
+        }
+    }
+
+    /// Makes `capital` the tile the player starts on, and puts the starting buildings there.
+    pub(crate) fn settle(&mut self, capital: Position, buildings: &AllBuildings) {
+        self.capital = Some(capital);
         //testing only
-        p.add_building(
-            Position { x: 0, y: 0 },
-            BuildingID(0),
-            buildings.get(&BuildingID(0)).unwrap(),
-            1,
-        );
-        p.add_building(
-            Position { x: 0, y: 0 },
-            BuildingID(1),
-            buildings.get(&BuildingID(1)).unwrap(),
-            1,
-        );
-        p
+        for id in [BuildingID(0), BuildingID(1)].iter() {
+            self.add_building(capital, *id, buildings.get(id).unwrap(), 1);
+        }
+    }
+
+    /// Number of buildings the player has on the tile at `pos`.
+    pub fn buildings_on(&self, pos: &Position) -> u32 {
+        self.buildings
+            .values()
+            .filter_map(|ob| ob.tiles.get(pos))
+            .sum()
+    }
+
+    /// Players this one can talk to and the number of tiles they share.
+    pub fn contacts(&self) -> &HashMap<Username, u32> {
+        &self.contacts
+    }
+
+    pub fn in_contact(&self, with: &Username) -> bool {
+        self.contacts.contains_key(with)
+    }
+
+    //Records one more tile shared with `with`.
+    pub(crate) fn add_contact(&mut self, with: &Username) {
+        *self.contacts.entry(with.clone()).or_insert(0) += 1;
+    }
+
+    //Records one less tile shared with `with`. The contact is lost when they share none.
+    pub(crate) fn remove_contact(&mut self, with: &Username) {
+        if let Some(count) = self.contacts.get_mut(with) {
+            *count -= 1;
+            if *count == 0 {
+                self.contacts.remove(with);
+            }
+        }
     }
 
     //Returns the maximum amount of buildings of the type `id` the player can currrently build.
@@ -178,6 +215,9 @@ impl Player {
         building: &Building,
         amount: u32,
     ) -> Result<()> {
+        if !self.research.is_unlocked(&id, building) {
+            return Err(Exception::Locked(id).into());
+        }
         if amount > self.max_buildable(vec![tiles.1], id, building) {
             return Err(anyhow!(format!(
                 "Can't build {:?} buildings of type ID{:?}, maximum is {:?}",
@@ -188,7 +228,10 @@ impl Player {
         }
         self.gen.needs_update = true;
         self.add_building(*tiles.0, id, building, amount);
-        tiles.1.resources.slots.get_mut(&id).unwrap().used += amount;
+        //only extractors take up resource slots
+        if let (true, Some(slots)) = (building.extractor, tiles.1.resources.slots.get_mut(&id)) {
+            slots.used += amount;
+        }
         Ok(())
     }
 
@@ -204,6 +247,9 @@ impl Player {
         ob.total += amount;
         ob.workers.1 += amount * building.max_workers;
         *ob.tiles.entry(pos).or_default() += amount;
+        for (resource, room) in building.storage.iter() {
+            self.resources.entry(*resource).or_default().maximum += room * amount;
+        }
     }
 
     //Attempts to tear down `amount` `building` in `tile`.
@@ -224,12 +270,14 @@ impl Player {
             return Err(anyhow!("Can't demolish more buildings than owned!"));
         };
         self.rm_building(pos, id, building, amount);
-        tile.resources.slots.get_mut(&id).unwrap().used -= amount;
-        let mut workers = self.buildings.get_mut(&id).unwrap().workers;
-
+        if let (true, Some(slots)) = (building.extractor, tile.resources.slots.get_mut(&id)) {
+            slots.used -= amount;
+        }
         //Adjusting workers count. Workers may need to be fired.
-        workers.0 -= (amount * building.max_workers) - (workers.1 - workers.0);
-        workers.1 -= amount * building.max_workers;
+        let workers = &mut self.buildings.get_mut(&id).unwrap().workers;
+        let fired = workers.0.saturating_sub(workers.1);
+        workers.0 -= fired;
+        self.people.release(fired);
         Ok(())
     }
 
@@ -245,6 +293,13 @@ impl Player {
         ob.total -= amount;
         ob.workers.1 -= amount * building.max_workers;
         *ob.tiles.get_mut(pos).unwrap() -= amount;
+        //what doesn't fit in the smaller stockpiles is lost
+        for (resource, room) in building.storage.iter() {
+            if let Some(stock) = self.resources.get_mut(resource) {
+                stock.maximum -= room * amount;
+                stock.current = stock.current.min(stock.maximum);
+            }
+        }
     }
 
     //Adds `amount` of `id` resource to the player if enough place is available.
@@ -277,6 +332,13 @@ impl Player {
         }
     }
 
+    /// Hires idle citizens to work in a building type.
+    ///
+    /// # Errors
+    /// This can fail because:
+    /// - the player does not own the said building
+    /// - there are not enough free jobs in buildings of this type
+    /// - there are not enough idle citizens
     pub fn hire(&mut self, id: BuildingID, amount: u32) -> Result<()> {
         match self.buildings.get_mut(&id) {
             Some(ob) => {
@@ -286,6 +348,7 @@ impl Player {
                         id
                     )))
                 } else {
+                    self.people.employ(amount)?;
                     ob.workers.0 += amount;
                     self.gen.needs_update = true;
                     Ok(())
@@ -316,6 +379,7 @@ impl Player {
                     )))
                 } else {
                     ob.workers.0 -= amount;
+                    self.people.release(amount);
                     self.gen.needs_update = true;
                     Ok(())
                 }
@@ -327,44 +391,326 @@ impl Player {
         }
     }
 
-    fn has_enough_for(&self, resources: &[ResourceEntry]) -> bool {
-        for res_entry in resources {
-            if let Some(res) = self.resources.get(&res_entry.id) {
-                if res.current >= res_entry.amount {
-                    continue;
+    /// Simulates a tick of the player's empire: production, population and research.
+    ///
+    /// Returns the events the player should be notified of.
+    pub fn update(
+        &mut self,
+        all_buildings: &AllBuildings,
+        graph: &DependencyGraph,
+        world: &mut HashMap<Position, Tile>,
+        low_deposit: u32,
+        population: &PopulationRules,
+    ) -> Vec<Event> {
+        let mut events = self.generate(all_buildings, graph, world, low_deposit);
+        self.live(population);
+        if let Some(building) = self.research.tick() {
+            events.push(Event::Unlocked(building));
+        }
+        self.last_tick += 1;
+        events
+    }
+
+    /// Simulates `ticks` ticks of the player's empire, with the same outcome as as many calls to `update`.
+    ///
+    /// Production is steady until a stockpile runs low, a deposit gets low, the population eats or a research
+    /// completes. The ticks in between are simulated all at once, which makes up for long absences quickly.
+    pub fn fast_forward(
+        &mut self,
+        ticks: u64,
+        all_buildings: &AllBuildings,
+        graph: &DependencyGraph,
+        world: &mut HashMap<Position, Tile>,
+        low_deposit: u32,
+        population: &PopulationRules,
+    ) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut left = ticks;
+        while left > 0 {
+            if self.gen.needs_update {
+                self.plan(graph, all_buildings, &HashMap::new());
+            }
+            let steady = if left > 1 {
+                self.steady(all_buildings, world, low_deposit, population)
+                    .min(left.min(u32::MAX as u64) as u32)
+            } else {
+                1
+            };
+            if steady > 1 {
+                events.extend(self.update_for(steady, all_buildings, world, population));
+            } else {
+                events.extend(self.update(all_buildings, graph, world, low_deposit, population));
+            }
+            left -= steady.max(1) as u64;
+        }
+        events
+    }
+
+    //Number of ticks during which the current plan holds: no stockpile can run out,
+    //every extractor gets its full share and the last tick is the first one when something else happens.
+    fn steady(
+        &self,
+        all_buildings: &AllBuildings,
+        world: &HashMap<Position, Tile>,
+        low_deposit: u32,
+        population: &PopulationRules,
+    ) -> u32 {
+        let mut ticks = self.people.until_meal(population);
+        if let Some((_, left)) = self.research.current() {
+            ticks = ticks.min(left);
+        }
+        for (resource, amount) in self.gen.map.iter() {
+            if *amount < 0 {
+                let stock = self
+                    .resources
+                    .get(resource)
+                    .map_or(0, |stock| stock.current);
+                ticks = ticks.min(stock / amount.unsigned_abs());
+            }
+        }
+        //deposits are kept above `low_deposit` and never emptied
+        for extraction in self.extractions(all_buildings) {
+            for (pos, share) in extraction.shares.iter().filter(|(_, share)| *share > 0) {
+                let deposit = world
+                    .get(pos)
+                    .and_then(|tile| tile.resources.amounts.get(&extraction.resource))
+                    .copied()
+                    .unwrap_or(0);
+                ticks = ticks.min(deposit.saturating_sub(low_deposit.max(1)) / share);
+            }
+        }
+        ticks
+    }
+
+    //Simulates `ticks` ticks at once, which `steady` made sure the current plan holds for.
+    fn update_for(
+        &mut self,
+        ticks: u32,
+        all_buildings: &AllBuildings,
+        world: &mut HashMap<Position, Tile>,
+        population: &PopulationRules,
+    ) -> Vec<Event> {
+        for extraction in self.extractions(all_buildings) {
+            let resource = extraction.resource;
+            for (pos, share) in extraction.shares {
+                if let Some(deposit) = world
+                    .get_mut(&pos)
+                    .and_then(|tile| tile.resources.amounts.get_mut(&resource))
+                {
+                    *deposit -= share * ticks;
+                }
+            }
+        }
+        let mut events = self.produce(ticks);
+        self.people.wait(ticks - 1);
+        self.live(population);
+        self.research.wait(ticks - 1);
+        if let Some(building) = self.research.tick() {
+            events.push(Event::Unlocked(building));
+        }
+        self.last_tick += ticks as u64;
+        events
+    }
+
+    /// Population update, ran every tick.
+    ///
+    /// Housing sets the maximum population. When it is time to eat, a fed population grows while a starving one shrinks,
+    /// as do populations over their maximum. Workers who die leave their job.
+    pub fn live(&mut self, rules: &PopulationRules) {
+        let housing: u32 = rules
+            .housing
+            .iter()
+            .map(|(id, room)| self.buildings.get(id).map_or(0, |ob| ob.total) * room)
+            .sum();
+        self.people.set_maximum(rules.base_maximum + housing);
+        if !self.people.hungry(rules) {
+            return;
+        }
+        let meal: HashMap<ResourceID, u32> = rules
+            .food
+            .iter()
+            .map(|(id, ration)| (*id, ration * self.people.total()))
+            .collect();
+        let mut lost = if self.pay(&meal).is_ok() {
+            self.people.grow(rules.growth);
+            0
+        } else {
+            //what little food is left gets eaten all the same
+            for id in meal.keys() {
+                if let Some(stock) = self.resources.get_mut(id) {
+                    stock.current = 0;
                 }
             }
-            return false;
+            rules.starvation
+        };
+        lost += self
+            .people
+            .total()
+            .saturating_sub(lost)
+            .saturating_sub(self.people.maximum());
+        let mut workers = self.people.shrink(lost);
+        if workers > 0 {
+            self.gen.needs_update = true;
+        }
+        //jobs are left in a fixed order so that all servers agree
+        let mut ids: Vec<BuildingID> = self.buildings.keys().copied().collect();
+        ids.sort_by_key(|id| id.0);
+        for id in ids {
+            let ob = self.buildings.get_mut(&id).unwrap();
+            let left = workers.min(ob.workers.0);
+            ob.workers.0 -= left;
+            workers -= left;
+        }
+    }
+
+    pub fn population(&self) -> &Population {
+        &self.people
+    }
+
+    /// Starts researching a building.
+    ///
+    /// The cost of the research is paid upfront. The building is unlocked after a number of ticks, see `Research::tick`.
+    ///
+    /// # Errors
+    /// This fails if:
+    /// - the building doesn't exist or is already unlocked
+    /// - one of its prerequisites is locked
+    /// - another building is being researched
+    /// - the player can't pay for the research
+    pub fn research(
+        &mut self,
+        id: BuildingID,
+        all_buildings: &AllBuildings,
+        rules: &ResearchRules,
+    ) -> Result<(), Exception> {
+        match all_buildings.get(&id) {
+            Some(building) if !self.research.is_unlocked(&id, building) => (),
+            _ => return Err(Exception::NotFound),
+        }
+        if let Some(prerequisite) = self.research.missing_prerequisite(&id, all_buildings) {
+            return Err(Exception::Locked(prerequisite));
+        }
+        if self.research.current().is_some() {
+            return Err(Exception::ResearchBusy);
+        }
+        self.pay(&self.research.cost(rules))?;
+        self.research.start(id, rules);
+        Ok(())
+    }
+
+    /// Takes `cost` out of the player's stockpiles, or nothing if the player can't afford all of it.
+    pub fn pay(&mut self, cost: &HashMap<ResourceID, u32>) -> Result<(), Exception> {
+        let entries = Player::entries(cost);
+        self.can_afford(&entries)?;
+        self.take(&entries);
+        Ok(())
+    }
+
+    //Gives back what `pay` took for `cost`, when what it was paid for fell through.
+    pub(crate) fn refund(&mut self, cost: &HashMap<ResourceID, u32>) {
+        self.store(&Player::entries(cost));
+    }
+
+    fn entries(cost: &HashMap<ResourceID, u32>) -> Vec<ResourceEntry> {
+        cost.iter()
+            .map(|(id, amount)| ResourceEntry {
+                id: *id,
+                amount: *amount,
+            })
+            .collect()
+    }
+
+    //Makes sure the player owns at least the amounts listed in `resources`.
+    fn can_afford(&self, resources: &[ResourceEntry]) -> Result<(), Exception> {
+        for (id, amount) in tally(resources)? {
+            match self.resources.get(&id) {
+                Some(res) if res.current >= amount => (),
+                _ => return Err(Exception::InsufficientResource(id)),
+            }
+        }
+        Ok(())
+    }
+
+    //Makes sure the player's stockpiles have enough free space to receive `incoming`.
+    //Resources in `outgoing` leave the stockpiles first and free up space.
+    fn can_store(
+        &self,
+        incoming: &[ResourceEntry],
+        outgoing: &[ResourceEntry],
+    ) -> Result<(), Exception> {
+        let freed = tally(outgoing)?;
+        for (id, amount) in tally(incoming)? {
+            //more room than a u32 holds is as good as u32::MAX for any amount
+            let free = match self.resources.get(&id) {
+                Some(res) => res.maximum - res.current,
+                None => Stockpile::new().maximum,
+            }
+            .saturating_add(freed.get(&id).copied().unwrap_or(0));
+            if free < amount {
+                return Err(Exception::InsufficientStockpile(id));
+            }
         }
-        true
+        Ok(())
+    }
+
+    //Adds `resources` to the stockpiles. Whatever doesn't fit is lost.
+    fn store(&mut self, resources: &[ResourceEntry]) {
+        for entry in resources {
+            let stock = self.resources.entry(entry.id).or_default();
+            stock.current = stock
+                .current
+                .saturating_add(entry.amount)
+                .min(stock.maximum);
+        }
+        self.gen.needs_update = true;
+    }
+
+    //Removes `resources` from the stockpiles. Expects `can_afford` to have been checked.
+    fn take(&mut self, resources: &[ResourceEntry]) {
+        for entry in resources {
+            self.resources.get_mut(&entry.id).unwrap().current -= entry.amount;
+        }
+        self.gen.needs_update = true;
     }
 
     /// Creating a trade offer to a peer.
     ///
-    /// Allows player to open a trade with another player.
-    /// The method fails if the player does not have enough resources to garuantee the deal.
+    /// Allows player `name` to open a trade with another player.
+    /// The offered resources are taken from the player's stockpiles and held in escrow
+    /// until the trade is accepted, refused or cancelled.
+    /// The offer is registered in the ledgers of both parties.
     ///
-    // # Example
-    //
-    // ```
-    //# use core::resources::ResourceID;
-    //# use core::trade::{ResourceEntry, Offer};
-    //# use core::Player;
-    //# use core::buildings::load_buildings;
-    //# let (all_buildings, _) = load_buildings();
-    //# let mut player = Player::new(all_buildings);
-    // let offer = Offer {offering: vec![ResourceEntry{id:ResourceID(0), amount: 12}], requesting: Vec::new()};
-    // assert_eq!(true, player.open_trade("Toude".to_string(), offer).is_ok())
-    //```
-    pub fn open_trade(&mut self, with: Username, offer: Offer) -> Result<()> {
-        if !self.has_enough_for(&offer.offering) {
-            return Err(anyhow!("Not enough resources to comply with the Offer."));
-        }
-        for res_entry in &offer.offering {
-            //we can unwrap because `has` made sure the key exists
-            self.resources.get_mut(&res_entry.id).unwrap().current -= res_entry.amount;
-        }
-        self.trades.outbound.entry(with).or_default().push(offer);
+    /// # Errors
+    /// This fails if:
+    /// - the offer is empty or made to the player itself
+    /// - the players aren't in contact, see `Player::contacts`
+    /// - the player does not have enough resources to garuantee the deal
+    pub fn open_trade(
+        &mut self,
+        name: &Username,
+        (with, peer): (&Username, &mut Player),
+        offer: Offer,
+    ) -> Result<(), Exception> {
+        if offer.is_empty() || name == with {
+            return Err(Exception::InvalidOffer);
+        }
+        if !self.in_contact(with) {
+            return Err(Exception::NoContact(with.clone()));
+        }
+        //the request is only checked against stockpiles once accepted, but it must add up already
+        tally(&offer.requesting)?;
+        self.can_afford(&offer.offering)?;
+        self.take(&offer.offering);
+        peer.trades
+            .inbound
+            .entry(name.clone())
+            .or_default()
+            .push(offer.clone());
+        self.trades
+            .outbound
+            .entry(with.clone())
+            .or_default()
+            .push(offer);
         Ok(())
     }
 
@@ -376,16 +722,37 @@ impl Player {
         with: &Username,
         offer: &Offer,
         offers: &mut HashMap<Username, Vec<Offer>>,
-    ) -> Result<()> {
+    ) -> Result<(), Exception> {
         if let Some(contracts) = offers.get_mut(with) {
-            for (idx, ongoing_offer) in contracts.iter().enumerate() {
-                if ongoing_offer == offer {
-                    contracts.remove(idx);
-                    return Ok(());
+            if let Some(idx) = contracts.iter().position(|ongoing| ongoing == offer) {
+                contracts.remove(idx);
+                if contracts.is_empty() {
+                    offers.remove(with);
                 }
+                return Ok(());
             }
         }
-        Err(anyhow!("No such trades with this player."))
+        Err(Exception::TradeNotFound)
+    }
+
+    //Closes the trade in both ledgers. `self` is the party which made the offer.
+    fn close_trade(
+        &mut self,
+        name: &Username,
+        (with, peer): (&Username, &mut Player),
+        offer: &Offer,
+    ) -> Result<(), Exception> {
+        //the offer must be checked in both ledgers before touching either of them
+        if !self
+            .trades
+            .outbound
+            .get(with)
+            .is_some_and(|offers| offers.contains(offer))
+        {
+            return Err(Exception::TradeNotFound);
+        }
+        Player::remove_offer(name, offer, &mut peer.trades.inbound)?;
+        Player::remove_offer(with, offer, &mut self.trades.outbound)
     }
 
     /// Cancel an active trade opened by the player.
@@ -394,28 +761,66 @@ impl Player {
     /// This restores the "reserved" resources. Excesses are disposed of.
     /// This may receive an Exception Response because the host server may have registered
     /// a refusal of agreement for the said trade.
-    pub fn cancel_trade(&mut self, with: &Username, offer: &Offer) -> Result<()> {
-        //first we make sure the Offer is still valid (ie: exists).
-        Player::remove_offer(with, offer, &mut self.trades.outbound)?;
+    pub fn cancel_trade(
+        &mut self,
+        name: &Username,
+        peer: (&Username, &mut Player),
+        offer: &Offer,
+    ) -> Result<(), Exception> {
+        self.close_trade(name, peer, offer)?;
+        self.store(&offer.offering);
+        Ok(())
+    }
+
+    /// Refuse a received trade offer
+    ///
+    /// Turns down an offer made by `peer`. The resources it held in escrow are returned to it.
+    /// Excesses are disposed of.
+    pub fn refuse_trade(
+        &mut self,
+        name: &Username,
+        (with, peer): (&Username, &mut Player),
+        offer: &Offer,
+    ) -> Result<(), Exception> {
+        peer.close_trade(with, (name, self), offer)?;
+        peer.store(&offer.offering);
         Ok(())
     }
 
     /// Accept a received trade offer
     ///
     /// Attempts to accept a trade offer made by a foreign party.
+    /// Both sides of the exchange are checked before any resource is moved so that a trade
+    /// either completes entirely or not at all.
     ///
     /// # Errors
     /// This fails if:
     /// - the trade cancellation was registered before the agreement.
     /// - the player does not have enough resources to complete fulfill the offer
-    pub fn accept_trade(&mut self, with: &Username, offer: &Offer) -> Result<()> {
-        if self.has_enough_for(&offer.requesting) {
-            Player::remove_offer(with, offer, &mut self.trades.inbound)?;
-            return Ok(());
+    /// - either party lacks the stockpile space to receive its share
+    pub fn accept_trade(
+        &mut self,
+        name: &Username,
+        (with, peer): (&Username, &mut Player),
+        offer: &Offer,
+    ) -> Result<(), Exception> {
+        if !self
+            .trades
+            .inbound
+            .get(with)
+            .is_some_and(|offers| offers.contains(offer))
+        {
+            return Err(Exception::TradeNotFound);
         }
-        Err(anyhow!(
-            "Player does not have enough resources to fulfill the offer."
-        ))
+        self.can_afford(&offer.requesting)?;
+        self.can_store(&offer.offering, &offer.requesting)?;
+        peer.can_store(&offer.requesting, &[])?;
+
+        peer.close_trade(with, (name, self), offer)?;
+        self.take(&offer.requesting);
+        self.store(&offer.offering);
+        peer.store(&offer.requesting);
+        Ok(())
     }
 
     /// Computes resources generations optimal ratios.
@@ -423,54 +828,51 @@ impl Player {
     //Creates a map with the lowest factor at which the building can work.
     //The key is the building and the value its current efficiency.
     //The later is calculated from available resources
-    //and takes the production chain into account
+    //and takes the production chain into account, see `production::solve`.
     //It needs to be ran everytime a resource's stockpile would reach 0 the next tick,
     //when the player updates its buildings or uses/deposists resources
-    //WARN: I think I forgot to take the prod of the other buildings into account ^^
-    //WARN: It doesn't have a high impact but may reduce perfs on low resources.
+    //`limits` caps the efficiency of extractors which can't get what they should out of their deposits.
     fn calc_ratios(
         &self,
-        tree: &DependencyTree,
+        graph: &DependencyGraph,
         all_buildings: &AllBuildings,
-    ) -> HashMap<BuildingID, f32> {
-        let mut ratios: HashMap<BuildingID, f32> = HashMap::new();
-        println!("Tree: {:?}", tree);
-        //We set buildings which don't consume resources to their optimal efficiency.
-        for building_id in tree.1.iter() {
-            ratios.insert(*building_id, 1.0_f32);
-        }
-
-        //We calculate the maximal efficiency of all buildings which consume resources.
-        for (resource, depends) in tree.0.iter() {
-            //the total amount of `resource` needed by the player's empire
-            let mut needed = 0.0;
-            for building_id in depends.iter() {
-                needed += (all_buildings
-                    .get(building_id)
-                    .unwrap()
-                    .consumed
-                    .get(resource)
-                    .unwrap()
-                    * self.buildings.get(building_id).unwrap().workers.0)
-                    as f32
-                    //we may know some buildings already can't work at full efficiency
-                    * match ratios.get(building_id) {
-                        Some(r) => *r,
-                        None => 1.0_f32,
-                    };
-            }
-            //the maximum efficiency possible considering available current amount of resource
-            let ratio: f32 =
-                1.0_f32.min((needed as u32 / self.resources.get(resource).unwrap().current) as f32);
-            //we apply the new ratio or keep a lower one to make sure no building will work at a higher efficiency than it can
-            for building_id in depends.iter() {
-                ratios
-                    .entry(*building_id)
-                    .and_modify(|crt_ratio| *crt_ratio = crt_ratio.min(ratio))
-                    .or_insert(ratio);
-            }
-        }
-        ratios
+        limits: &HashMap<BuildingID, Efficiency>,
+    ) -> HashMap<BuildingID, Efficiency> {
+        production::solve_limited(all_buildings, graph, &self.workers(), &self.stock(), limits)
+            .efficiency
+    }
+
+    //Plans the production of the next tick.
+    fn plan(
+        &mut self,
+        graph: &DependencyGraph,
+        all_buildings: &AllBuildings,
+        limits: &HashMap<BuildingID, Efficiency>,
+    ) {
+        self.gen.ratios = self.calc_ratios(graph, all_buildings, limits);
+        self.gen.map = production::net(all_buildings, &self.workers(), &self.gen.ratios);
+        self.gen.needs_update = false;
+    }
+
+    /// Describes how well the economy of the player runs, see `production::report`.
+    pub fn economy(&self, graph: &DependencyGraph, all_buildings: &AllBuildings) -> Report {
+        production::report(all_buildings, graph, &self.workers(), &self.stock())
+    }
+
+    //Current content of each stockpile.
+    fn stock(&self) -> HashMap<ResourceID, u32> {
+        self.resources
+            .iter()
+            .map(|(id, stock)| (*id, stock.current))
+            .collect()
+    }
+
+    //Number of employees of each building type.
+    fn workers(&self) -> HashMap<BuildingID, u32> {
+        self.buildings
+            .iter()
+            .map(|(id, ob)| (*id, ob.workers.0))
+            .collect()
     }
 
     //true if one of the condition for a new ratios map is needed.
@@ -478,27 +880,165 @@ impl Player {
     //The other conditions are: resource usage that cause a stockpile drop
     //and the construction/destruction of a building.
     //These **must** be checked so that the updates are consistent.
-    pub fn generate(&mut self, all_buildings: &AllBuildings, tree: &DependencyTree) -> () {
+    //
+    //Extractors draw their production from the deposits of the tiles they are built on.
+    //The returned events notify the player of the patches which ran out during this tick.
+    pub fn generate(
+        &mut self,
+        all_buildings: &AllBuildings,
+        graph: &DependencyGraph,
+        world: &mut HashMap<Position, Tile>,
+        low_deposit: u32,
+    ) -> Vec<Event> {
         if self.gen.needs_update {
-            self.gen.ratios = self.calc_ratios(tree, all_buildings);
-            self.gen.make_gen_map(all_buildings, &self.buildings);
-            self.gen.needs_update = false;
+            self.plan(graph, all_buildings, &HashMap::new());
         };
+        let (limits, exhausted) = self.extract(all_buildings, world, low_deposit);
+        if !limits.is_empty() {
+            //the buildings using what the extractors make can only use what was actually extracted
+            self.plan(graph, all_buildings, &limits);
+            //the deposits change every tick, the plan is made again from scratch next time
+            self.gen.needs_update = true;
+        }
+        let mut events: Vec<Event> = exhausted
+            .into_iter()
+            .map(|(pos, resource)| Event::World(World::Exhausted { pos, resource }))
+            .collect();
+        events.extend(self.produce(1));
+        events
+    }
+
+    //Adds `ticks` times the planned production to the stockpiles.
+    //Events are sorted by the tick they happen at, as if the ticks were simulated one by one.
+    fn produce(&mut self, ticks: u32) -> Vec<Event> {
+        let mut events = Vec::new();
         for (resource, amount) in self.gen.map.iter() {
-            let mut crt = self.resources.entry(*resource).or_default();
-            crt.current = crt.current.wrapping_add(*amount as u32).min(crt.maximum);
+            let amount = *amount;
+            let crt = self.resources.entry(*resource).or_default();
+            let (current, maximum) = (crt.current as i64, crt.maximum as i64);
+            let produced = (current + amount as i64 * ticks as i64).max(0);
+            crt.current = produced.min(maximum) as u32;
+
+            //the player is only told once that production is being wasted,
+            //until a tick when it fits in the stockpile again
+            if amount <= 0 || current + amount as i64 <= maximum {
+                self.gen.full.remove(resource);
+            }
+            if amount > 0 && produced > maximum && self.gen.full.insert(*resource) {
+                //the tick when the stockpile overflows
+                let tick = (maximum - current) / amount as i64 + 1;
+                events.push((tick, Event::StockpileFull(*resource)));
+            }
 
             //checking if there enough resources for the next tick
-            if *amount < 0 {
-                match self.resources.get(resource) {
-                    Some(x) if x.current < amount.abs() as u32 => self.gen.needs_update = true,
-                    _ => (),
+            if amount < 0 && crt.current < amount.unsigned_abs() {
+                self.gen.needs_update = true;
+            }
+        }
+        events.sort_by_key(|(tick, _)| *tick);
+        events.into_iter().map(|(_, event)| event).collect()
+    }
+
+    /// Takes the production of extractors out of tile deposits.
+    ///
+    /// Production is split between the tiles of a building type according to the number of buildings on each.
+    /// Once a deposit falls under `low_deposit` its yield drops proportionally to what is left.
+    /// Returns the efficiency of the extractors which got less than planned, along with the patches that ran out.
+    /// Patches are only reported when they run out, not while regrowth keeps them barely above empty.
+    /// They can be reported again once they grew back to `low_deposit`.
+    fn extract(
+        &mut self,
+        all_buildings: &AllBuildings,
+        world: &mut HashMap<Position, Tile>,
+        low_deposit: u32,
+    ) -> (HashMap<BuildingID, Efficiency>, Vec<(Position, ResourceID)>) {
+        let mut limits = HashMap::new();
+        let mut exhausted = Vec::new();
+        for extraction in self.extractions(all_buildings) {
+            let resource = extraction.resource;
+            let mut total = 0;
+            for (pos, share) in extraction.shares {
+                let deposit = match world
+                    .get_mut(&pos)
+                    .and_then(|tile| tile.resources.amounts.get_mut(&resource))
+                {
+                    Some(deposit) if *deposit > 0 => deposit,
+                    _ => continue,
                 };
-            };
+                if *deposit >= low_deposit {
+                    self.gen.exhausted.remove(&(pos, resource));
+                }
+                let extracted = if *deposit >= low_deposit {
+                    share
+                } else {
+                    (share * *deposit / low_deposit).max(share.min(1))
+                }
+                .min(*deposit);
+                *deposit -= extracted;
+                total += extracted;
+                if *deposit == 0 && self.gen.exhausted.insert((pos, resource)) {
+                    exhausted.push((pos, resource));
+                }
+            }
+            if total < extraction.planned {
+                //rounded down so that the new plan never makes more than what was extracted
+                let id = extraction.building;
+                let ratio = self.gen.ratios.get(&id).copied().unwrap_or(0) as u64;
+                let limit = (ratio * total as u64 / extraction.planned as u64) as Efficiency;
+                let crt = limits.entry(id).or_insert(limit);
+                *crt = (*crt).min(limit);
+            }
         }
+        (limits, exhausted)
+    }
+
+    //What each extractor should take out of the tiles it is built on every tick, according to the plan.
+    //The last tile takes what rounding left over.
+    //Tiles are walked in a fixed order so that the rounding is the same on all servers.
+    fn extractions(&self, all_buildings: &AllBuildings) -> Vec<Extraction> {
+        let mut extractions = Vec::new();
+        for (id, ob) in self.buildings.iter() {
+            let building = all_buildings.get(id).unwrap();
+            let mut tiles: Vec<(&Position, &u32)> =
+                ob.tiles.iter().filter(|(_, count)| **count > 0).collect();
+            let built: u32 = tiles.iter().map(|(_, count)| **count).sum();
+            if !building.extractor || built == 0 {
+                continue;
+            }
+            tiles.sort_by_key(|(pos, _)| (pos.x, pos.y));
+            for (resource, amount) in building.produced.iter() {
+                let planned = self.gen.output(id, *amount, ob.workers.0);
+                let mut left = planned;
+                let mut shares = Vec::with_capacity(tiles.len());
+                for (idx, (pos, count)) in tiles.iter().enumerate() {
+                    let share = if idx + 1 == tiles.len() {
+                        left
+                    } else {
+                        planned * **count / built
+                    };
+                    left -= share;
+                    shares.push((**pos, share));
+                }
+                extractions.push(Extraction {
+                    building: *id,
+                    resource: *resource,
+                    planned,
+                    shares,
+                });
+            }
+        }
+        extractions
     }
 }
 
+//What the extractors of a building type take of a resource every tick, in total and from each tile.
+struct Extraction {
+    building: BuildingID,
+    resource: ResourceID,
+    planned: u32,
+    shares: Vec<(Position, u32)>,
+}
+
 /// Player  resource generator
 ///
 /// This can be conceived as a cache to speed up calculations.
@@ -507,7 +1047,11 @@ impl Player {
 pub struct Generator {
     needs_update: bool,
     map: GenMap,
-    ratios: HashMap<BuildingID, f32>,
+    ratios: HashMap<BuildingID, Efficiency>,
+    //resources whose production overflows the stockpile
+    full: HashSet<ResourceID>,
+    //patches the player was told ran out
+    exhausted: HashSet<(Position, ResourceID)>,
 }
 
 impl Generator {
@@ -516,30 +1060,18 @@ impl Generator {
             map: GenMap::new(),
             ratios: HashMap::new(),
             needs_update: true,
+            full: HashSet::new(),
+            exhausted: HashSet::new(),
         }
     }
-    pub fn make_gen_map(
-        &mut self,
-        all_buildings: &AllBuildings,
-        player_buildings: &HashMap<BuildingID, OwnedBuilding>,
-    ) -> () {
-        let mut gen = GenMap::new();
-        for (building, _) in player_buildings.iter() {
-            //adding resources produced per tick
-            for (resource, amount) in all_buildings.get(building).unwrap().produced.iter() {
-                *gen.entry(*resource).or_insert(0) +=
-                    ((*amount * player_buildings.get(building).unwrap().workers.0) as f32
-                        * self.ratios.get(building).unwrap()) as i32;
-            }
-            //substracting resources consumed per tick
-            for (resource, amount) in all_buildings.get(building).unwrap().consumed.iter() {
-                *gen.entry(*resource).or_insert(0) -=
-                    ((*amount * player_buildings.get(building).unwrap().workers.0) as f32
-                        * self.ratios.get(building).unwrap()) as i32;
-            }
-        }
 
-        self.map = gen
+    //Amount of a resource made or used per tick by `workers` employees of a building type with a per-worker `amount`.
+    fn output(&self, building: &BuildingID, amount: u32, workers: u32) -> u32 {
+        production::output(
+            amount,
+            workers,
+            self.ratios.get(building).copied().unwrap_or(0),
+        )
     }
 }
 
@@ -548,3 +1080,366 @@ impl Default for Generator {
         Generator::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{buildings, player};
+
+    fn stock(player: &Player, id: u8) -> u32 {
+        player
+            .resources
+            .get(&ResourceID(id))
+            .map_or(0, |s| s.current)
+    }
+
+    fn parties() -> (Username, Player, Username, Player) {
+        let all = buildings();
+        let (mut alice, mut bob) = (player(&all), player(&all));
+        alice.add_contact(&"bob".to_string());
+        bob.add_contact(&"alice".to_string());
+        alice.deposit(ResourceID(0), 50).unwrap();
+        bob.deposit(ResourceID(1), 20).unwrap();
+        ("alice".to_string(), alice, "bob".to_string(), bob)
+    }
+
+    fn offer() -> Offer {
+        Offer {
+            offering: vec![ResourceEntry {
+                id: ResourceID(0),
+                amount: 30,
+            }],
+            requesting: vec![ResourceEntry {
+                id: ResourceID(1),
+                amount: 10,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_trade_accepted() {
+        let (a, mut alice, b, mut bob) = parties();
+        alice.open_trade(&a, (&b, &mut bob), offer()).unwrap();
+        assert_eq!(stock(&alice, 0), 20);
+        assert!(bob.trades.inbound[&a].contains(&offer()));
+
+        bob.accept_trade(&b, (&a, &mut alice), &offer()).unwrap();
+        assert_eq!((stock(&alice, 0), stock(&alice, 1)), (20, 10));
+        assert_eq!((stock(&bob, 0), stock(&bob, 1)), (30, 10));
+        assert!(alice.trades.outbound.is_empty() && bob.trades.inbound.is_empty());
+    }
+
+    #[test]
+    fn test_trade_cancelled() {
+        let (a, mut alice, b, mut bob) = parties();
+        alice.open_trade(&a, (&b, &mut bob), offer()).unwrap();
+        alice.cancel_trade(&a, (&b, &mut bob), &offer()).unwrap();
+        assert_eq!(stock(&alice, 0), 50);
+        assert!(matches!(
+            bob.accept_trade(&b, (&a, &mut alice), &offer()),
+            Err(Exception::TradeNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_trade_full_stockpile() {
+        let (a, mut alice, b, mut bob) = parties();
+        bob.deposit(ResourceID(0), 80).unwrap();
+        alice.open_trade(&a, (&b, &mut bob), offer()).unwrap();
+        assert!(matches!(
+            bob.accept_trade(&b, (&a, &mut alice), &offer()),
+            Err(Exception::InsufficientStockpile(ResourceID(0)))
+        ));
+        //nothing moved and the offer is still open
+        assert_eq!((stock(&bob, 0), stock(&bob, 1)), (80, 20));
+        assert!(bob.trades.inbound[&a].contains(&offer()));
+    }
+
+    #[test]
+    fn test_trade_overflow() {
+        let (a, mut alice, b, mut bob) = parties();
+        let entries = |amounts: &[u32]| {
+            amounts
+                .iter()
+                .map(|amount| ResourceEntry {
+                    id: ResourceID(0),
+                    amount: *amount,
+                })
+                .collect::<Vec<_>>()
+        };
+        //the totals would wrap around to 1, which alice can afford
+        let huge = Offer {
+            offering: entries(&[u32::MAX, 2]),
+            requesting: entries(&[1]),
+        };
+        assert!(matches!(
+            alice.open_trade(&a, (&b, &mut bob), huge),
+            Err(Exception::InvalidOffer)
+        ));
+        let greedy = Offer {
+            offering: entries(&[1]),
+            requesting: entries(&[u32::MAX, 2]),
+        };
+        assert!(matches!(
+            alice.open_trade(&a, (&b, &mut bob), greedy),
+            Err(Exception::InvalidOffer)
+        ));
+        assert_eq!(stock(&alice, 0), 50);
+        assert!(alice.trades.outbound.is_empty() && bob.trades.inbound.is_empty());
+
+        //a lot of room freed by what leaves doesn't overflow either
+        assert!(bob
+            .can_store(&entries(&[10]), &entries(&[u32::MAX]))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_deposit_depletion() {
+        let all = buildings();
+        let graph = DependencyGraph::new(&all);
+        let mut player = player(&all);
+        player.hire(BuildingID(0), 2).unwrap();
+        let mut tile = Tile::new();
+        tile.resources.amounts.insert(ResourceID(0), 25);
+        let mut world = HashMap::new();
+        world.insert(Position { x: 0, y: 0 }, tile);
+
+        //10 wood per tick until the deposit is under 10, then half as much for the 5 left
+        for _ in 0..2 {
+            assert!(player.generate(&all, &graph, &mut world, 10).is_empty());
+        }
+        let events = player.generate(&all, &graph, &mut world, 10);
+        assert!(matches!(
+            events.as_slice(),
+            [Event::World(World::Exhausted {
+                resource: ResourceID(0),
+                ..
+            })]
+        ));
+        assert_eq!(stock(&player, 0), 25);
+        assert!(player.generate(&all, &graph, &mut world, 10).is_empty());
+        assert_eq!(stock(&player, 0), 25);
+
+        //a patch that regrows a little is emptied again without the player being told again
+        let pos = Position { x: 0, y: 0 };
+        for _ in 0..3 {
+            world
+                .get_mut(&pos)
+                .unwrap()
+                .resources
+                .amounts
+                .insert(ResourceID(0), 1);
+            assert!(player.generate(&all, &graph, &mut world, 10).is_empty());
+        }
+        assert_eq!(stock(&player, 0), 28);
+        //once it grew back it can run out again
+        world
+            .get_mut(&pos)
+            .unwrap()
+            .resources
+            .amounts
+            .insert(ResourceID(0), 10);
+        assert_eq!(player.generate(&all, &graph, &mut world, 10).len(), 1);
+    }
+
+    #[test]
+    fn test_starved_consumers() {
+        let mut all = buildings();
+        all.insert(
+            BuildingID(3),
+            serde_json::from_str(r#"["Sawmill", false, [], {"3": 1}, {"0": 1}, 5, {}]"#).unwrap(),
+        );
+        let graph = DependencyGraph::new(&all);
+        let mut player = player(&all);
+        let pos = Position { x: 0, y: 0 };
+        player.add_building(pos, BuildingID(3), &all[&BuildingID(3)], 1);
+        player.hire(BuildingID(0), 2).unwrap();
+        player.hire(BuildingID(3), 3).unwrap();
+        let mut tile = Tile::new();
+        tile.resources.amounts.insert(ResourceID(0), 2);
+        let mut world = HashMap::new();
+        world.insert(pos, tile);
+
+        //the lumberyard should make 10 wood but only finds 2, the sawmill can't use more than that
+        player.generate(&all, &graph, &mut world, 1);
+        let made = stock(&player, 3);
+        assert!(made > 0);
+        assert!(made + stock(&player, 0) <= 2);
+
+        //nothing is left to extract so the sawmill stops
+        for _ in 0..3 {
+            player.generate(&all, &graph, &mut world, 1);
+        }
+        assert_eq!(stock(&player, 3), made);
+        assert!(made + stock(&player, 0) <= 2);
+    }
+
+    #[test]
+    fn test_update() {
+        let all = buildings();
+        let graph = DependencyGraph::new(&all);
+        let mut player = player(&all);
+        player.hire(BuildingID(0), 2).unwrap();
+        let mut world = HashMap::new();
+        let mut tile = Tile::new();
+        tile.resources.amounts.insert(ResourceID(0), 1000);
+        world.insert(Position { x: 0, y: 0 }, tile);
+        let rules = PopulationRules {
+            food: HashMap::new(),
+            meal: 100,
+            growth: 0,
+            starvation: 0,
+            base_maximum: 10,
+            housing: HashMap::new(),
+        };
+        let research = ResearchRules {
+            base_cost: HashMap::new(),
+            growth: 1,
+            ticks: 3,
+        };
+        player.research.start(BuildingID(1), &research);
+
+        //an offline player catching up goes through the same ticks as a connected one
+        let mut unlocked = Vec::new();
+        for _ in 0..5 {
+            for event in player.update(&all, &graph, &mut world, 10, &rules) {
+                if let Event::Unlocked(id) = event {
+                    unlocked.push(id);
+                }
+            }
+        }
+        assert_eq!(stock(&player, 0), 50);
+        assert_eq!(unlocked, vec![BuildingID(1)]);
+        assert_eq!(player.last_tick, 5);
+    }
+
+    #[test]
+    fn test_fast_forward() {
+        let mut all = buildings();
+        all.insert(
+            BuildingID(3),
+            serde_json::from_str(r#"["Sawmill", false, [], {"3": 2}, {"0": 3}, 5, {}]"#).unwrap(),
+        );
+        let graph = DependencyGraph::new(&all);
+        let rules = PopulationRules {
+            food: vec![(ResourceID(3), 1)].into_iter().collect(),
+            meal: 20,
+            growth: 1,
+            starvation: 2,
+            base_maximum: 12,
+            housing: HashMap::new(),
+        };
+        let research = ResearchRules {
+            base_cost: HashMap::new(),
+            growth: 1,
+            ticks: 150,
+        };
+        let pos = Position { x: 0, y: 0 };
+        let empire = || {
+            let mut player = player(&all);
+            player.add_building(pos, BuildingID(3), &all[&BuildingID(3)], 1);
+            player.hire(BuildingID(0), 2).unwrap();
+            player.hire(BuildingID(3), 2).unwrap();
+            player.research.start(BuildingID(1), &research);
+            let mut tile = Tile::new();
+            tile.resources.amounts.insert(ResourceID(0), 3000);
+            let mut world = HashMap::new();
+            world.insert(pos, tile);
+            (player, world)
+        };
+
+        //the deposit runs out, the population grows, starves and the research completes on the way
+        let (mut stepped, mut stepped_world) = empire();
+        let mut events = Vec::new();
+        for _ in 0..1000 {
+            events.extend(stepped.update(&all, &graph, &mut stepped_world, 100, &rules));
+        }
+        let (mut player, mut world) = empire();
+        let batched = player.fast_forward(1000, &all, &graph, &mut world, 100, &rules);
+
+        //events of the same tick come in no particular order
+        let sorted = |events: Vec<Event>| {
+            let mut events: Vec<String> = events.iter().map(|e| format!("{:?}", e)).collect();
+            events.sort();
+            events
+        };
+        assert_eq!(batched.len(), events.len());
+        assert_eq!(sorted(batched), sorted(events));
+        assert_eq!(
+            world[&pos].resources.amounts,
+            stepped_world[&pos].resources.amounts
+        );
+        assert_eq!(world[&pos].resources.amounts[&ResourceID(0)], 0);
+        for id in 0..4 {
+            assert_eq!(stock(&player, id), stock(&stepped, id));
+        }
+        assert_eq!(player.people.total(), stepped.people.total());
+        assert_eq!(player.people.idle(), stepped.people.idle());
+        assert_eq!(player.research.current(), None);
+        assert_eq!(player.last_tick, 1000);
+    }
+
+    #[test]
+    fn test_population() {
+        let all = buildings();
+        let mut player = player(&all);
+        player.hire(BuildingID(0), 3).unwrap();
+        assert_eq!(player.people.idle(), 2);
+        assert!(matches!(
+            player.hire(BuildingID(1), 3).unwrap_err().downcast(),
+            Ok(Exception::InsufficientCitizens)
+        ));
+
+        let mut rules = PopulationRules {
+            food: vec![(ResourceID(3), 1)].into_iter().collect(),
+            meal: 1,
+            growth: 2,
+            starvation: 3,
+            base_maximum: 6,
+            housing: HashMap::new(),
+        };
+        //starving citizens die, idle ones first
+        player.live(&rules);
+        assert_eq!((player.people.total(), player.people.idle()), (2, 0));
+        assert_eq!(player.buildings[&BuildingID(0)].workers.0, 2);
+
+        //fed citizens grow up to the maximum
+        player.deposit(ResourceID(3), 50).unwrap();
+        rules.base_maximum = 3;
+        player.live(&rules);
+        assert_eq!((player.people.total(), player.people.idle()), (3, 1));
+        assert_eq!(stock(&player, 3), 48);
+    }
+
+    #[test]
+    fn test_storage() {
+        let all = buildings();
+        let graph = DependencyGraph::new(&all);
+        let mut player = player(&all);
+        let (pos, mut tile) = (Position { x: 0, y: 0 }, Tile::new());
+        let warehouse = &all[&BuildingID(2)];
+        player
+            .build((&pos, &mut tile), BuildingID(2), warehouse, 1)
+            .unwrap();
+        player.deposit(ResourceID(0), 198).unwrap();
+
+        //the lumberyard fills up the stockpile, which is only reported once
+        tile.resources.amounts.insert(ResourceID(0), 1000);
+        let mut world = vec![(pos, tile)].into_iter().collect();
+        player.hire(BuildingID(0), 1).unwrap();
+        let full = |events: Vec<Event>| {
+            events
+                .iter()
+                .any(|event| matches!(event, Event::StockpileFull(ResourceID(0))))
+        };
+        assert!(full(player.generate(&all, &graph, &mut world, 0)));
+        assert!(!full(player.generate(&all, &graph, &mut world, 0)));
+        assert_eq!(stock(&player, 0), 200);
+
+        let tile = world.get_mut(&pos).unwrap();
+        player
+            .demolish((&pos, tile), BuildingID(2), warehouse, 1)
+            .unwrap();
+        assert_eq!(stock(&player, 0), 100);
+    }
+}
@@ -0,0 +1,121 @@
+use crate::buildings::BuildingID;
+use crate::resources::ResourceID;
+use crate::response::Exception;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// How populations live, read from the game data.
+///
+/// Every `meal` ticks each citizen eats the `food` rations. A fed population grows by `growth` citizens
+/// until it reaches its maximum while a starving one loses `starvation` citizens.
+/// The maximum population is `base_maximum` plus the room offered by each of the `housing` buildings.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PopulationRules {
+    pub food: HashMap<ResourceID, u32>,
+    pub meal: u32,
+    pub growth: u32,
+    pub starvation: u32,
+    pub base_maximum: u32,
+    pub housing: HashMap<BuildingID, u32>,
+}
+
+/// Player's subjects
+///
+/// Holds data related to the population of a player's empire.
+/// Most useful for dispatching citizens to jobs as well as managing the total population.
+/// Citizens who aren't `idle` are working in one of the player's buildings.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Population {
+    //here the definition in the repo requires a mapping (ie: HashMap) -> this makes it easier to build upon but less elegant
+    //we respectively have `idle`, `total` and `maximum` -> consider making it an array instead
+    idle: u32,
+    total: u32,
+    maximum: u32,
+    //ticks since the last meal
+    #[serde(default)]
+    hunger: u32,
+}
+
+impl Population {
+    pub fn new() -> Population {
+        Population {
+            idle: 5,
+            total: 5,
+            maximum: 10,
+            hunger: 0,
+        }
+    }
+
+    pub fn idle(&self) -> u32 {
+        self.idle
+    }
+
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+
+    pub fn maximum(&self) -> u32 {
+        self.maximum
+    }
+
+    pub(crate) fn set_maximum(&mut self, maximum: u32) {
+        self.maximum = maximum;
+    }
+
+    /// Puts `amount` idle citizens to work.
+    pub fn employ(&mut self, amount: u32) -> Result<(), Exception> {
+        if self.idle < amount {
+            return Err(Exception::InsufficientCitizens);
+        }
+        self.idle -= amount;
+        Ok(())
+    }
+
+    /// Makes `amount` workers idle.
+    pub fn release(&mut self, amount: u32) {
+        self.idle = (self.idle + amount).min(self.total);
+    }
+
+    //True when it is time to eat.
+    pub(crate) fn hungry(&mut self, rules: &PopulationRules) -> bool {
+        self.hunger += 1;
+        if self.hunger >= rules.meal {
+            self.hunger = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    //Number of ticks until the population eats, counting the tick of the meal.
+    pub(crate) fn until_meal(&self, rules: &PopulationRules) -> u32 {
+        rules.meal.saturating_sub(self.hunger).max(1)
+    }
+
+    //Lets `ticks` ticks pass without a meal, see `until_meal`.
+    pub(crate) fn wait(&mut self, ticks: u32) {
+        self.hunger += ticks;
+    }
+
+    //New citizens are born idle, up to the maximum population.
+    pub(crate) fn grow(&mut self, amount: u32) {
+        let born = amount.min(self.maximum.saturating_sub(self.total));
+        self.total += born;
+        self.idle += born;
+    }
+
+    //Idle citizens are the first to go. Returns how many workers are lost as well.
+    pub(crate) fn shrink(&mut self, amount: u32) -> u32 {
+        let amount = amount.min(self.total);
+        let workers = amount.saturating_sub(self.idle);
+        self.idle -= amount - workers;
+        self.total -= amount;
+        workers
+    }
+}
+
+impl Default for Population {
+    fn default() -> Self {
+        Population::new()
+    }
+}
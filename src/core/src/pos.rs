@@ -1,67 +1,411 @@
 use crate::tile::Position;
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 
-const VERTICE_COUPLE: [Position; 2] = [Position { x: 1, y: 0 }, Position { x: 0, y: -1 }];
+//Axial directions of the hexagonal neighbourhood, in the order in which rings are walked.
+const HEX_DIRECTIONS: [Position; 6] = [
+    Position { x: 1, y: 0 },
+    Position { x: 1, y: -1 },
+    Position { x: 0, y: -1 },
+    Position { x: -1, y: 0 },
+    Position { x: -1, y: 1 },
+    Position { x: 0, y: 1 },
+];
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct PosGenerator {
-    step: u32,
-    last_pos: Position,
-    tiles: Vec<Position>,
-    vertices: Vec<Position>,
+/// Shape of the map's grid.
+///
+/// With a `Square` topology a tile touches the 8 tiles around it and rings are squares.
+/// With a `Hexagonal` topology positions are axial coordinates, a tile touches 6 others and rings are hexagons.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Copy, Clone)]
+pub enum Topology {
+    Square,
+    Hexagonal,
 }
 
-impl PosGenerator {
-    pub fn new(step: u32) -> PosGenerator {
-        PosGenerator {
-            step: step,
-            tiles: Vec::new(),
-            vertices: Vec::new(),
-            last_pos: Position { x: 0, y: 0 },
+impl FromStr for Topology {
+    type Err = anyhow::Error;
+
+    /// Reads `square` or `hex`.
+    fn from_str(name: &str) -> Result<Topology, Self::Err> {
+        match name {
+            "square" => Ok(Topology::Square),
+            "hex" => Ok(Topology::Hexagonal),
+            _ => Err(anyhow!("unknown topology {}", name)),
         }
     }
+}
 
-    fn total_pos(&self) -> u32 {
-        let mut nbr = 1;
-        for i in 0..self.step {
-            nbr += 2 * i;
+impl Topology {
+    /// Number of positions in the `ring`-th ring around the origin. The origin is ring 0.
+    pub fn ring_len(&self, ring: u32) -> u32 {
+        match (ring, self) {
+            (0, _) => 1,
+            (_, Topology::Square) => 8 * ring,
+            (_, Topology::Hexagonal) => 6 * ring,
         }
-        nbr
     }
 
-    fn next_vertices(&mut self) -> () {
-        let mut sign: i32 = 1;
-        for _ in 0..2 {
-            for i in 0..2 {
-                self.vertices.push(Position {
-                    x: VERTICE_COUPLE[i].x * sign * self.step as i32,
-                    y: VERTICE_COUPLE[i].y * sign * self.step as i32,
-                });
+    /// The `index`-th position of the `ring`-th ring.
+    ///
+    /// # Panics
+    /// If `index` is out of the ring, see `ring_len`.
+    pub fn ring_pos(&self, ring: u32, index: u32) -> Position {
+        assert!(index < self.ring_len(ring), "Index out of the ring!");
+        if ring == 0 {
+            return Position { x: 0, y: 0 };
+        }
+        let (k, side, offset) = match self {
+            Topology::Square => (ring as i32, index / (2 * ring), (index % (2 * ring)) as i32),
+            Topology::Hexagonal => (ring as i32, index / ring, (index % ring) as i32),
+        };
+        match self {
+            //each side is 2k long and starts at a corner
+            Topology::Square => match side {
+                0 => Position {
+                    x: k,
+                    y: offset - k,
+                },
+                1 => Position {
+                    x: k - offset,
+                    y: k,
+                },
+                2 => Position {
+                    x: -k,
+                    y: k - offset,
+                },
+                _ => Position {
+                    x: offset - k,
+                    y: -k,
+                },
+            },
+            Topology::Hexagonal => {
+                let corner = Topology::hex_corner(k, side as usize);
+                let dir = HEX_DIRECTIONS[side as usize];
+                Position {
+                    x: corner.x + dir.x * offset,
+                    y: corner.y + dir.y * offset,
+                }
             }
-            sign *= -1;
         }
     }
 
-    fn next_tiles(&mut self) -> () {
-        if self.vertices.len() == 0 {
-            self.next_vertices();
+    //The first position of the `side`-th side of the hexagonal ring `k`.
+    fn hex_corner(k: i32, side: usize) -> Position {
+        let mut corner = Position {
+            x: HEX_DIRECTIONS[4].x * k,
+            y: HEX_DIRECTIONS[4].y * k,
+        };
+        for dir in HEX_DIRECTIONS.iter().take(side) {
+            corner.x += dir.x * k;
+            corner.y += dir.y * k;
+        }
+        corner
+    }
+
+    /// The ring a position belongs to. This is its distance to the origin.
+    pub fn ring_of(&self, pos: &Position) -> u32 {
+        self.distance(&Position { x: 0, y: 0 }, pos)
+    }
+
+    /// The ring and index of a position, such that `ring_pos(ring, index) == pos`.
+    pub fn locate(&self, pos: &Position) -> (u32, u32) {
+        let ring = self.ring_of(pos);
+        if ring == 0 {
+            return (0, 0);
+        }
+        let k = ring as i32;
+        let index = match self {
+            Topology::Square => {
+                let (side, offset) = if pos.x == k && pos.y < k {
+                    (0, pos.y + k)
+                } else if pos.y == k && pos.x > -k {
+                    (1, k - pos.x)
+                } else if pos.x == -k && pos.y > -k {
+                    (2, k - pos.y)
+                } else {
+                    (3, pos.x + k)
+                };
+                side * 2 * ring + offset as u32
+            }
+            Topology::Hexagonal => (0..6)
+                .find_map(|side| {
+                    let corner = Topology::hex_corner(k, side);
+                    let dir = HEX_DIRECTIONS[side];
+                    let (dx, dy) = (pos.x - corner.x, pos.y - corner.y);
+                    let offset = if dir.x != 0 { dx / dir.x } else { dy / dir.y };
+                    if (0..k).contains(&offset) && dx == dir.x * offset && dy == dir.y * offset {
+                        Some(side as u32 * ring + offset as u32)
+                    } else {
+                        None
+                    }
+                })
+                .expect("A position always belongs to a side of its ring."),
+        };
+        (ring, index)
+    }
+
+    /// Number of steps between two positions, moving from a tile to one of its neighbours at each step.
+    ///
+    /// Distances too long for a `u32`, only found on hexagonal maps between far apart positions, are `u32::MAX`.
+    pub fn distance(&self, from: &Position, to: &Position) -> u32 {
+        //the difference of two coordinates doesn't fit in an i32
+        let (dx, dy) = (to.x as i64 - from.x as i64, to.y as i64 - from.y as i64);
+        let steps = match self {
+            Topology::Square => dx.abs().max(dy.abs()),
+            Topology::Hexagonal => (dx.abs() + dy.abs() + (dx + dy).abs()) / 2,
+        };
+        steps.min(u32::MAX as i64) as u32
+    }
+
+    /// True if the two positions are next to each other.
+    pub fn adjacent(&self, a: &Position, b: &Position) -> bool {
+        self.distance(a, b) == 1
+    }
+
+    /// All positions at `radius` from `center`. Those whose coordinates don't fit in an `i32` are left out.
+    pub fn ring(&self, center: &Position, radius: u32) -> Vec<Position> {
+        (0..self.ring_len(radius))
+            .filter_map(|index| center.checked_add(&self.ring_pos(radius, index)))
+            .collect()
+    }
+
+    /// The tiles touching `pos`.
+    pub fn neighbours(&self, pos: &Position) -> Vec<Position> {
+        self.ring(pos, 1)
+    }
+
+    /// All positions at most `radius` away from `center`.
+    pub fn area(&self, center: &Position, radius: u32) -> Vec<Position> {
+        (0..=radius)
+            .flat_map(|ring| self.ring(center, ring))
+            .collect()
+    }
+
+    /// The tiles crossed by a straight line going from `from` to `to`, both included.
+    ///
+    /// Each step goes to a neighbour of the previous tile. Both ends must be in range, see `Position::LIMIT`.
+    //The line is sampled at each step and rounded to the closest tile. This is all done with integers
+    //and the samples are slightly nudged so that ties are always broken the same way.
+    pub fn line(&self, from: &Position, to: &Position) -> Vec<Position> {
+        let steps = self.distance(from, to) as i64;
+        if steps == 0 {
+            return vec![*from];
         }
-        let Position { x, y } = self.vertices.remove(0);
-        for i in 0..(x | y) {
-            self.tiles.push(Position {
-                x: (self.last_pos.x | self.last_pos.x + i),
-                y: (self.last_pos.y | self.last_pos.y + i),
+        const PRECISION: i64 = 1000;
+        let denominator = steps * PRECISION;
+        let round = |numerator: i64| (2 * numerator + denominator).div_euclid(2 * denominator);
+        let (a, b) = (from.cube(), to.cube());
+        (0..=steps)
+            .map(|i| {
+                let lerp =
+                    |a: i64, b: i64, nudge: i64| (a * steps + (b - a) * i) * PRECISION + nudge;
+                match self {
+                    Topology::Square => Position {
+                        x: round(lerp(a.0, b.0, 1)) as i32,
+                        y: round(lerp(a.1, b.1, 2)) as i32,
+                    },
+                    Topology::Hexagonal => {
+                        let exact = [lerp(a.0, b.0, 1), lerp(a.1, b.1, 2), lerp(a.2, b.2, -3)];
+                        let mut cube = [round(exact[0]), round(exact[1]), round(exact[2])];
+                        //the coordinate which was rounded the most is deduced from the others
+                        let error = |axis: usize| (cube[axis] * denominator - exact[axis]).abs();
+                        if error(0) > error(1) && error(0) > error(2) {
+                            cube[0] = -cube[1] - cube[2];
+                        } else if error(1) > error(2) {
+                            cube[1] = -cube[0] - cube[2];
+                        }
+                        Position {
+                            x: cube[0] as i32,
+                            y: cube[1] as i32,
+                        }
+                    }
+                }
             })
+            .collect()
+    }
+
+    /// True if nothing blocks the view between the two positions.
+    ///
+    /// `opaque` tells whether a tile blocks the view. The ends of the line can't block it.
+    pub fn in_sight<F: Fn(&Position) -> bool>(
+        &self,
+        from: &Position,
+        to: &Position,
+        opaque: F,
+    ) -> bool {
+        let line = self.line(from, to);
+        line.iter()
+            .skip(1)
+            .take(line.len().saturating_sub(2))
+            .all(|pos| !opaque(pos))
+    }
+
+    /// Number of positions in rings `0..ring`.
+    pub fn positions_within(&self, ring: u32) -> u64 {
+        let ring = ring as u64;
+        match (ring, self) {
+            (0, _) => 0,
+            (_, Topology::Square) => (2 * ring - 1).pow(2),
+            (_, Topology::Hexagonal) => 3 * ring * (ring - 1) + 1,
+        }
+    }
+}
+
+/// Spiral iterator over the positions of the map
+///
+/// Yields the origin, then all the positions of the first ring, then those of the second ring... and so on.
+/// This makes it possible to grow the world around its center while keeping it compact.
+///
+/// The generator is entirely defined by the ring and index of the next position, so it can be saved along
+/// with `GameData` and resumed from where it stopped.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PosGenerator {
+    topology: Topology,
+    ring: u32,
+    index: u32,
+}
+
+impl PosGenerator {
+    pub fn new(topology: Topology) -> PosGenerator {
+        PosGenerator {
+            topology,
+            ring: 0,
+            index: 0,
         }
     }
+
+    pub fn topology(&self) -> Topology {
+        self.topology
+    }
+
+    /// Ring and index of the next position to be yielded.
+    pub fn state(&self) -> (u32, u32) {
+        (self.ring, self.index)
+    }
+
+    /// Number of positions yielded so far.
+    pub fn yielded(&self) -> u64 {
+        self.topology.positions_within(self.ring) + self.index as u64
+    }
 }
 
 impl Iterator for PosGenerator {
     type Item = Position;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.tiles.len() == 0 {
-            self.next_tiles();
+        let pos = self.topology.ring_pos(self.ring, self.index);
+        self.index += 1;
+        if self.index == self.topology.ring_len(self.ring) {
+            self.ring += 1;
+            self.index = 0;
+        }
+        Some(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_spiral_is_unique_and_compact() {
+        for topology in [Topology::Square, Topology::Hexagonal].iter() {
+            let total = topology.positions_within(6) as usize;
+            let positions: Vec<Position> = PosGenerator::new(*topology).take(total).collect();
+            let unique: HashSet<&Position> = positions.iter().collect();
+            assert_eq!(unique.len(), total);
+            assert!(positions.iter().all(|pos| topology.ring_of(pos) < 6));
         }
-        Some(self.tiles.remove(0))
+    }
+
+    #[test]
+    fn test_extremes() {
+        let (min, max) = (
+            Position {
+                x: i32::MIN,
+                y: i32::MIN,
+            },
+            Position {
+                x: i32::MAX,
+                y: i32::MAX,
+            },
+        );
+        assert!(!min.in_range() && !max.in_range());
+        assert_eq!(Topology::Square.distance(&min, &max), u32::MAX);
+        assert_eq!(Topology::Hexagonal.distance(&min, &max), u32::MAX);
+        assert_eq!(Topology::Hexagonal.ring_of(&min), u32::MAX);
+        assert!(!Topology::Square.adjacent(&max, &min));
+        //positions past the edge of the coordinates have no neighbours there
+        assert_eq!(Topology::Square.neighbours(&max).len(), 3);
+        assert_eq!(Topology::Hexagonal.neighbours(&min).len(), 2);
+        let edge = Position {
+            x: i32::MAX,
+            y: i32::MIN,
+        };
+        assert_eq!(Topology::Hexagonal.ring_of(&edge), i32::MAX as u32 + 1);
+    }
+
+    #[test]
+    fn test_locate() {
+        for topology in [Topology::Square, Topology::Hexagonal].iter() {
+            let mut gen = PosGenerator::new(*topology);
+            for _ in 0..200 {
+                let state = gen.state();
+                let pos = gen.next().unwrap();
+                assert_eq!(topology.locate(&pos), state);
+            }
+        }
+    }
+
+    #[test]
+    fn test_square_first_ring() {
+        let ring: HashSet<Position> = PosGenerator::new(Topology::Square)
+            .skip(1)
+            .take(8)
+            .collect();
+        for x in -1..=1 {
+            for y in -1..=1 {
+                assert_eq!(ring.contains(&Position { x, y }), (x, y) != (0, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_neighbours() {
+        let origin = Position { x: 0, y: 0 };
+        assert_eq!(Topology::Square.neighbours(&origin).len(), 8);
+        let hex = Topology::Hexagonal.neighbours(&origin);
+        assert_eq!(hex.len(), 6);
+        assert!(!hex.contains(&Position { x: 1, y: 1 }));
+        assert!(hex.contains(&Position { x: 1, y: -1 }));
+    }
+
+    #[test]
+    fn test_line() {
+        for topology in [Topology::Square, Topology::Hexagonal].iter() {
+            let (from, to) = (Position { x: -3, y: 5 }, Position { x: 4, y: -1 });
+            let line = topology.line(&from, &to);
+            assert_eq!(line.len() as u32, topology.distance(&from, &to) + 1);
+            assert_eq!((line[0], *line.last().unwrap()), (from, to));
+            assert!(line.windows(2).all(|w| topology.adjacent(&w[0], &w[1])));
+        }
+        let wall = Position { x: 1, y: 0 };
+        let (from, to) = (Position { x: 0, y: 0 }, Position { x: 2, y: 0 });
+        assert!(!Topology::Hexagonal.in_sight(&from, &to, |pos| *pos == wall));
+        assert!(Topology::Hexagonal.in_sight(&from, &wall, |pos| *pos == wall));
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut gen = PosGenerator::new(Topology::Hexagonal);
+        gen.nth(20);
+        let mut resumed: PosGenerator =
+            serde_json::from_str(&serde_json::to_string(&gen).unwrap()).unwrap();
+        assert_eq!(resumed.yielded(), 21);
+        assert_eq!(
+            gen.take(30).collect::<Vec<_>>(),
+            resumed.by_ref().take(30).collect::<Vec<_>>()
+        );
     }
 }
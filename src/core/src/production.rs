@@ -0,0 +1,398 @@
+use crate::buildings::{AllBuildings, BuildingID, DependencyGraph};
+use crate::player::GenMap;
+use crate::resources::ResourceID;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// How hard a building works, in millionths of its full capacity.
+///
+/// Production is computed with integers only so that all servers reach the exact same result.
+/// This is what lets the host overrule other servers when they disagree.
+pub type Efficiency = u32;
+
+/// Efficiency of a building working at full capacity.
+pub const FULL: Efficiency = 1_000_000;
+
+//How many times the resources are walked before stopping the circular recipes which keep slowing down.
+//A chain of N steps needs N passes to settle, circular ones may never settle exactly.
+const MAX_PASSES: usize = 64;
+
+//Amount of `resource` going through buildings of type `id`, in millionths.
+fn flow(
+    amounts: &HashMap<ResourceID, u32>,
+    resource: &ResourceID,
+    workers: u32,
+    efficiency: Efficiency,
+) -> u128 {
+    amounts.get(resource).copied().unwrap_or(0) as u128 * workers as u128 * efficiency as u128
+}
+
+/// Outcome of `solve`.
+#[derive(Debug, Default)]
+pub struct Solution {
+    /// Efficiency of every building with workers.
+    pub efficiency: HashMap<BuildingID, Efficiency>,
+    /// The resource which last slowed down each building that can't work at full capacity.
+    pub bottlenecks: HashMap<BuildingID, ResourceID>,
+}
+
+/// Finds the efficiency at which each building can work.
+///
+/// `workers` is the number of employees of each building type and `stock` the content of the stockpiles.
+/// A building consuming a resource can't use more of it in a tick than what is stocked plus what is produced
+/// during that same tick. Whenever that is the case, all the consumers of the resource slow down in the same proportion.
+/// This is repeated until every resource is balanced, so that slowdowns propagate along production chains.
+///
+/// Circular recipes which can't sustain themselves would only ever get closer to a halt.
+/// When they still haven't settled after a while, their buildings that keep slowing down are stopped.
+///
+/// Buildings without workers aren't part of the result.
+pub fn solve(
+    all_buildings: &AllBuildings,
+    graph: &DependencyGraph,
+    workers: &HashMap<BuildingID, u32>,
+    stock: &HashMap<ResourceID, u32>,
+) -> Solution {
+    solve_limited(all_buildings, graph, workers, stock, &HashMap::new())
+}
+
+/// Same as `solve`, except that the buildings of `limits` can't work faster than the efficiency given.
+///
+/// This is how extractors whose deposits ran low slow down the buildings using what they make.
+pub fn solve_limited(
+    all_buildings: &AllBuildings,
+    graph: &DependencyGraph,
+    workers: &HashMap<BuildingID, u32>,
+    stock: &HashMap<ResourceID, u32>,
+    limits: &HashMap<BuildingID, Efficiency>,
+) -> Solution {
+    //ordered maps make the rounding independent from hashing
+    let mut efficiency: BTreeMap<BuildingID, Efficiency> = workers
+        .iter()
+        .filter(|(id, amount)| **amount > 0 && all_buildings.contains_key(id))
+        .map(|(id, _)| (*id, limits.get(id).map_or(FULL, |limit| (*limit).min(FULL))))
+        .collect();
+    let consumed: BTreeSet<ResourceID> = graph
+        .consumers
+        .iter()
+        .filter(|(_, consumers)| consumers.iter().any(|id| efficiency.contains_key(id)))
+        .map(|(resource, _)| *resource)
+        .collect();
+    let mut bottlenecks = HashMap::new();
+
+    loop {
+        let mut slowed = BTreeSet::new();
+        for _ in 0..MAX_PASSES {
+            slowed.clear();
+            for resource in consumed.iter() {
+                let (mut demand, mut supply) = (
+                    0,
+                    stock.get(resource).copied().unwrap_or(0) as u128 * FULL as u128,
+                );
+                for (id, crt) in efficiency.iter() {
+                    let building = &all_buildings[id];
+                    demand += flow(&building.consumed, resource, workers[id], *crt);
+                    supply += flow(&building.produced, resource, workers[id], *crt);
+                }
+                if demand <= supply {
+                    continue;
+                }
+                for (id, crt) in efficiency.iter_mut() {
+                    if all_buildings[id].consumed.contains_key(resource) {
+                        let reduced = (*crt as u128 * supply / demand) as Efficiency;
+                        if reduced < *crt {
+                            *crt = reduced;
+                            slowed.insert(*id);
+                            bottlenecks.insert(*id, *resource);
+                        }
+                    }
+                }
+            }
+            if slowed.is_empty() {
+                break;
+            }
+        }
+        let stalled: Vec<BuildingID> = slowed
+            .into_iter()
+            .filter(|id| efficiency[id] > 0 && graph.in_cycle(id))
+            .collect();
+        if stalled.is_empty() {
+            break;
+        }
+        //each round stops at least one building so this always ends
+        for id in stalled {
+            efficiency.insert(id, 0);
+        }
+    }
+    Solution {
+        efficiency: efficiency.into_iter().collect(),
+        bottlenecks,
+    }
+}
+
+/// What a player's economy looks like, so that bottlenecks can be displayed.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    /// Efficiency of each building with the current stockpiles.
+    pub current: HashMap<BuildingID, Efficiency>,
+    /// Efficiency each building can keep up once the stockpiles are empty.
+    pub steady: HashMap<BuildingID, Efficiency>,
+    /// The resource holding back each building which can't keep up working at full capacity.
+    pub bottlenecks: HashMap<BuildingID, ResourceID>,
+    /// Circular recipes in which the player has working buildings.
+    pub cycles: Vec<Vec<BuildingID>>,
+}
+
+/// Solves the economy both with the current stockpiles and without any stock.
+pub fn report(
+    all_buildings: &AllBuildings,
+    graph: &DependencyGraph,
+    workers: &HashMap<BuildingID, u32>,
+    stock: &HashMap<ResourceID, u32>,
+) -> Report {
+    let current = solve(all_buildings, graph, workers, stock).efficiency;
+    let steady = solve(all_buildings, graph, workers, &HashMap::new());
+    let cycles = graph
+        .cycles()
+        .iter()
+        .filter(|cycle| cycle.iter().any(|id| current.contains_key(id)))
+        .cloned()
+        .collect();
+    Report {
+        current,
+        steady: steady.efficiency,
+        bottlenecks: steady.bottlenecks,
+        cycles,
+    }
+}
+
+/// Net amount of each resource made (or used when negative) in a tick.
+///
+/// Amounts are rounded down, which never takes more from a stockpile than what the solver allowed.
+pub fn net(
+    all_buildings: &AllBuildings,
+    workers: &HashMap<BuildingID, u32>,
+    efficiency: &HashMap<BuildingID, Efficiency>,
+) -> GenMap {
+    let mut totals: BTreeMap<ResourceID, i128> = BTreeMap::new();
+    for (id, crt) in efficiency.iter() {
+        let building = &all_buildings[id];
+        for resource in building.produced.keys() {
+            *totals.entry(*resource).or_insert(0) +=
+                flow(&building.produced, resource, workers[id], *crt) as i128;
+        }
+        for resource in building.consumed.keys() {
+            *totals.entry(*resource).or_insert(0) -=
+                flow(&building.consumed, resource, workers[id], *crt) as i128;
+        }
+    }
+    totals
+        .into_iter()
+        .map(|(resource, total)| (resource, total.div_euclid(FULL as i128) as i32))
+        .collect()
+}
+
+/// Amount made or used in a tick by `workers` employees each handling `amount` at `efficiency`.
+pub fn output(amount: u32, workers: u32, efficiency: Efficiency) -> u32 {
+    (amount as u64 * workers as u64 * efficiency as u64 / FULL as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buildings::Building;
+
+    //(resource, amount) couples produced and consumed by a building
+    type Recipe<'a> = (&'a [(u8, u32)], &'a [(u8, u32)]);
+
+    fn chain(recipes: &[Recipe]) -> (AllBuildings, DependencyGraph) {
+        let amounts = |list: &[(u8, u32)]| list.iter().map(|(r, a)| (ResourceID(*r), *a)).collect();
+        let all: AllBuildings = recipes
+            .iter()
+            .enumerate()
+            .map(|(id, (produced, consumed))| {
+                let building = Building {
+                    name: id.to_string(),
+                    extractor: consumed.is_empty(),
+                    prerequisites: Vec::new(),
+                    produced: amounts(produced),
+                    consumed: amounts(consumed),
+                    max_workers: 10,
+                    construction_cost: HashMap::new(),
+                    storage: HashMap::new(),
+                };
+                (BuildingID(id as u8), building)
+            })
+            .collect();
+        let graph = DependencyGraph::new(&all);
+        (all, graph)
+    }
+
+    fn run(
+        recipes: &[Recipe],
+        workers: &[u32],
+        stock: &[(u8, u32)],
+    ) -> (HashMap<BuildingID, Efficiency>, GenMap) {
+        let (all, graph) = chain(recipes);
+        let workers = workers
+            .iter()
+            .enumerate()
+            .map(|(id, w)| (BuildingID(id as u8), *w))
+            .collect();
+        let stock = stock.iter().map(|(r, a)| (ResourceID(*r), *a)).collect();
+        let efficiency = solve(&all, &graph, &workers, &stock).efficiency;
+        let map = net(&all, &workers, &efficiency);
+        (efficiency, map)
+    }
+
+    fn eff(result: &HashMap<BuildingID, Efficiency>, id: u8) -> Efficiency {
+        result.get(&BuildingID(id)).copied().unwrap_or(0)
+    }
+
+    fn gen(map: &GenMap, id: u8) -> i32 {
+        map.get(&ResourceID(id)).copied().unwrap_or(0)
+    }
+
+    #[test]
+    fn test_free_buildings_work_fully() {
+        let (efficiency, map) = run(&[(&[(0, 5)], &[])], &[3], &[]);
+        assert_eq!(eff(&efficiency, 0), FULL);
+        assert_eq!(gen(&map, 0), 15);
+    }
+
+    #[test]
+    fn test_zero_stockpile() {
+        //nothing produces the consumed resource and nothing is stocked
+        let (efficiency, map) = run(&[(&[(1, 1)], &[(0, 2)])], &[4], &[]);
+        assert_eq!(eff(&efficiency, 0), 0);
+        assert_eq!((gen(&map, 0), gen(&map, 1)), (0, 0));
+    }
+
+    #[test]
+    fn test_stock_covers_demand() {
+        let (efficiency, map) = run(&[(&[(1, 1)], &[(0, 2)])], &[4], &[(0, 8)]);
+        assert_eq!(eff(&efficiency, 0), FULL);
+        assert_eq!((gen(&map, 0), gen(&map, 1)), (-8, 4));
+    }
+
+    #[test]
+    fn test_produced_and_consumed_in_same_tick() {
+        //wood made during the tick feeds the sawmill during that same tick
+        let (efficiency, map) = run(&[(&[(0, 4)], &[]), (&[(1, 1)], &[(0, 4)])], &[1, 1], &[]);
+        assert_eq!((eff(&efficiency, 0), eff(&efficiency, 1)), (FULL, FULL));
+        assert_eq!((gen(&map, 0), gen(&map, 1)), (0, 1));
+    }
+
+    #[test]
+    fn test_multi_step_chain() {
+        //ore -> ingots -> tools, with too little ore for everyone
+        let (efficiency, map) = run(
+            &[
+                (&[(0, 2)], &[]),
+                (&[(1, 2)], &[(0, 4)]),
+                (&[(2, 1)], &[(1, 4)]),
+            ],
+            &[1, 1, 1],
+            &[],
+        );
+        assert_eq!(eff(&efficiency, 1), FULL / 2);
+        assert_eq!(eff(&efficiency, 2), FULL / 4);
+        assert_eq!((gen(&map, 0), gen(&map, 1), gen(&map, 2)), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_shared_resource() {
+        //two consumers share the shortage equally, stock included
+        let (efficiency, map) = run(
+            &[
+                (&[(0, 3)], &[]),
+                (&[(1, 1)], &[(0, 3)]),
+                (&[(2, 1)], &[(0, 6)]),
+            ],
+            &[1, 1, 1],
+            &[(0, 3)],
+        );
+        assert_eq!(
+            (eff(&efficiency, 1), eff(&efficiency, 2)),
+            (FULL * 2 / 3, FULL * 2 / 3)
+        );
+        assert_eq!(gen(&map, 0), -3);
+    }
+
+    #[test]
+    fn test_never_overdraws() {
+        //thirds can't be represented exactly, rounding must stay on the safe side
+        for stock in 0..10 {
+            let (_, map) = run(
+                &[
+                    (&[(0, 1)], &[]),
+                    (&[(1, 1)], &[(0, 3)]),
+                    (&[(1, 2)], &[(0, 7)]),
+                ],
+                &[1, 2, 3],
+                &[(0, stock)],
+            );
+            assert!(stock as i32 + gen(&map, 0) >= 0);
+        }
+    }
+
+    #[test]
+    fn test_cycle_detection() {
+        //0 and 1 feed each other, 2 only feeds itself and 3 is not in a loop
+        let (_, graph) = chain(&[
+            (&[(0, 1)], &[(1, 1)]),
+            (&[(1, 1)], &[(0, 1)]),
+            (&[(2, 2)], &[(2, 1)]),
+            (&[(3, 1)], &[(0, 1)]),
+        ]);
+        assert_eq!(
+            graph.cycles(),
+            &[vec![BuildingID(0), BuildingID(1)], vec![BuildingID(2)]]
+        );
+        assert!(!graph.in_cycle(&BuildingID(3)));
+        assert_eq!(graph.producers[&ResourceID(0)], vec![BuildingID(0)]);
+        assert_eq!(
+            graph.consumers[&ResourceID(0)],
+            vec![BuildingID(1), BuildingID(3)]
+        );
+    }
+
+    #[test]
+    fn test_sustainable_cycle() {
+        let (efficiency, _) = run(
+            &[(&[(0, 2)], &[(1, 1)]), (&[(1, 1)], &[(0, 1)])],
+            &[1, 1],
+            &[],
+        );
+        assert_eq!((eff(&efficiency, 0), eff(&efficiency, 1)), (FULL, FULL));
+    }
+
+    #[test]
+    fn test_failing_cycle_stops() {
+        //each turn of the loop loses a hundredth of what goes through it
+        let (efficiency, map) = run(
+            &[(&[(0, 99)], &[(1, 100)]), (&[(1, 100)], &[(0, 100)])],
+            &[1, 1],
+            &[],
+        );
+        assert_eq!((eff(&efficiency, 0), eff(&efficiency, 1)), (0, 0));
+        assert_eq!((gen(&map, 0), gen(&map, 1)), (0, 0));
+    }
+
+    #[test]
+    fn test_report_bottlenecks() {
+        //ore -> ingots, ore runs short once the stock is used up
+        let (all, graph) = chain(&[(&[(0, 2)], &[]), (&[(1, 1)], &[(0, 4)])]);
+        let workers = [(BuildingID(0), 1), (BuildingID(1), 1)]
+            .iter()
+            .copied()
+            .collect();
+        let stock = [(ResourceID(0), 10)].iter().copied().collect();
+        let report = report(&all, &graph, &workers, &stock);
+        assert_eq!(report.current[&BuildingID(1)], FULL);
+        assert_eq!(report.steady[&BuildingID(1)], FULL / 2);
+        assert_eq!(report.bottlenecks.get(&BuildingID(1)), Some(&ResourceID(0)));
+        assert!(!report.bottlenecks.contains_key(&BuildingID(0)));
+        assert!(report.cycles.is_empty());
+    }
+}
@@ -0,0 +1,138 @@
+use crate::buildings::{AllBuildings, Building, BuildingID};
+use crate::resources::ResourceID;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// How costly research is, read from the game data.
+///
+/// Unlocking a building costs `base_cost` multiplied by `growth` once for every building already researched,
+/// and takes `ticks` more updates each time. This makes each unlock harder than the previous one,
+/// whatever the order in which buildings are researched.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResearchRules {
+    pub base_cost: HashMap<ResourceID, u32>,
+    pub growth: u32,
+    pub ticks: u32,
+}
+
+/// A player's progress through the buildings.
+///
+/// Buildings without prerequisites are always available. All others must be researched,
+/// which is only possible once their prerequisites are available themselves.
+/// Only one building can be researched at a time.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Research {
+    unlocked: HashSet<BuildingID>,
+    //the building being researched and the number of ticks left
+    current: Option<(BuildingID, u32)>,
+}
+
+impl Research {
+    pub fn new() -> Research {
+        Research::default()
+    }
+
+    /// True if the player may build `building`, whose ID is `id`.
+    pub fn is_unlocked(&self, id: &BuildingID, building: &Building) -> bool {
+        building.prerequisites.is_empty() || self.unlocked.contains(id)
+    }
+
+    /// The first prerequisite of `id` which isn't unlocked yet, if any.
+    pub fn missing_prerequisite(
+        &self,
+        id: &BuildingID,
+        all_buildings: &AllBuildings,
+    ) -> Option<BuildingID> {
+        all_buildings.get(id).and_then(|building| {
+            building
+                .prerequisites
+                .iter()
+                .find(|prerequisite| {
+                    !all_buildings
+                        .get(prerequisite)
+                        .is_some_and(|required| self.is_unlocked(prerequisite, required))
+                })
+                .copied()
+        })
+    }
+
+    /// Number of buildings researched so far.
+    pub fn researched(&self) -> u32 {
+        self.unlocked.len() as u32
+    }
+
+    /// What the next research costs.
+    pub fn cost(&self, rules: &ResearchRules) -> HashMap<ResourceID, u32> {
+        let factor = rules.growth.saturating_pow(self.researched());
+        rules
+            .base_cost
+            .iter()
+            .map(|(id, amount)| (*id, amount.saturating_mul(factor)))
+            .collect()
+    }
+
+    /// How many ticks the next research takes.
+    pub fn duration(&self, rules: &ResearchRules) -> u32 {
+        rules.ticks.saturating_mul(self.researched() + 1)
+    }
+
+    /// The building being researched and the number of ticks left.
+    pub fn current(&self) -> Option<(BuildingID, u32)> {
+        self.current
+    }
+
+    //Starts researching `id`. The cost must have been paid.
+    pub(crate) fn start(&mut self, id: BuildingID, rules: &ResearchRules) {
+        self.current = Some((id, self.duration(rules)));
+    }
+
+    //Lets `ticks` ticks of research pass, as many calls to `tick` would, without completing it.
+    pub(crate) fn wait(&mut self, ticks: u32) {
+        if let Some((_, left)) = self.current.as_mut() {
+            *left = (*left).saturating_sub(ticks).max(1);
+        }
+    }
+
+    /// Advances the current research by one tick. Returns the building if it just got unlocked.
+    pub fn tick(&mut self) -> Option<BuildingID> {
+        match self.current.as_mut() {
+            Some((_, left)) if *left > 1 => {
+                *left -= 1;
+                None
+            }
+            Some((id, _)) => {
+                let id = *id;
+                self.unlocked.insert(id);
+                self.current = None;
+                Some(id)
+            }
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_research_gets_harder() {
+        let rules = ResearchRules {
+            base_cost: vec![(ResourceID(0), 10)].into_iter().collect(),
+            growth: 3,
+            ticks: 2,
+        };
+        let mut research = Research::new();
+        for (nbr, cost) in [10, 30, 90].iter().enumerate() {
+            assert_eq!(research.cost(&rules)[&ResourceID(0)], *cost);
+            research.start(BuildingID(nbr as u8), &rules);
+            let ticks = research.duration(&rules);
+            for _ in 1..ticks {
+                assert_eq!(research.tick(), None);
+            }
+            assert_eq!(research.tick(), Some(BuildingID(nbr as u8)));
+            assert_eq!(ticks, 2 * (nbr as u32 + 1));
+        }
+        assert_eq!(research.tick(), None);
+    }
+}
@@ -1,39 +1,180 @@
+pub use crate::accounts::Token;
 use crate::buildings::BuildingID;
+use crate::net::MAX_MESSAGE;
+use crate::pack::Definitions;
 use crate::player::Username;
+use crate::production::Report;
 use crate::resources::ResourceID;
 use crate::tile::{Position, Tile};
 use crate::trade::Offer;
+use anyhow::{anyhow, Result};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::de::Error as _;
 use serde::{Deserialize, Serialize};
+use serde::{Deserializer, Serializer};
 use std::cmp::PartialEq;
 use std::error::Error;
 use std::fmt;
+use std::io::{Read, Write};
 
-/// A token used as a pass for a user
+/// Version of the protocol spoken by this build.
 ///
-/// BEWARE! This is currently just a random u32 that offers
-/// no crypto guarantees!
-#[derive(Debug, Deserialize, Serialize, Copy, Clone, PartialEq)]
-pub struct Token(u32);
-
-impl Token {
-    pub fn new() -> Token {
-        //a very secure & unique token
-        Token(3421545)
-    }
-}
+/// It must be bumped whenever a `Response` is serialized differently, so that peers notice instead of failing to
+/// read each other. The serialization of every variant is checked against `fixtures/protocol/v{version}.json`.
+/// Only the handshake is checked against the fixtures of older versions, as it must be understood by all of them.
+pub const PROTOCOL_VERSION: u32 = 3;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Response {
+    ///The handshake. Its serialization must stay the same across all versions of the protocol.
+    Hello(Hello),
     Auth(Auth),
     Event(Event),
     Exception(Exception),
 }
 
+///The first message of a client, to which the server replies with its own `Hello` or `Exception::Incompatible`.
+///
+///Clients list the capabilities they support, the server replies with the ones it supports too.
+///These are in use for all the messages following the reply of the server, in both directions.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Hello {
+    pub version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+impl Hello {
+    ///Greeting with the version of this build.
+    pub fn new(capabilities: Vec<Capability>) -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    ///The reply of a server supporting `supported` to this greeting.
+    pub fn reply(&self, supported: &[Capability]) -> Result<Hello, Exception> {
+        if self.version != PROTOCOL_VERSION {
+            return Err(Exception::Incompatible(PROTOCOL_VERSION));
+        }
+        let mut capabilities = Vec::new();
+        for capability in &self.capabilities {
+            let known = *capability != Capability::Unknown;
+            if known && supported.contains(capability) && !capabilities.contains(capability) {
+                capabilities.push(*capability);
+            }
+        }
+        Ok(Hello::new(capabilities))
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    ///The codec of the messages following this reply. The handshake itself is always in JSON.
+    pub fn codec(&self) -> &'static dyn Codec {
+        if self.supports(Capability::Binary) {
+            &Binary
+        } else {
+            &Json
+        }
+    }
+
+    ///Writes a message following this reply: `response` in the agreed codec, compressed if agreed on.
+    pub fn encode(&self, response: &Response) -> Result<Vec<u8>> {
+        let message = self.codec().encode(response)?;
+        if !self.supports(Capability::Compression) {
+            return Ok(message);
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&message)?;
+        Ok(encoder.finish()?)
+    }
+
+    ///Reads a message following this reply, see `encode`.
+    ///
+    ///# Errors
+    ///Besides messages that can't be read, this fails if they are larger than `MAX_MESSAGE` once decompressed.
+    pub fn decode(&self, message: &[u8]) -> Result<Response> {
+        if !self.supports(Capability::Compression) {
+            return self.codec().decode(message);
+        }
+        let mut decoded = Vec::new();
+        DeflateDecoder::new(message)
+            .take(MAX_MESSAGE as u64 + 1)
+            .read_to_end(&mut decoded)?;
+        if decoded.len() > MAX_MESSAGE {
+            return Err(anyhow!("the message is too large once decompressed"));
+        }
+        self.codec().decode(&decoded)
+    }
+}
+
+///How responses are written on the wire, and read back.
+pub trait Codec {
+    fn encode(&self, response: &Response) -> Result<Vec<u8>>;
+    fn decode(&self, message: &[u8]) -> Result<Response>;
+}
+
+///Responses as JSON, easy to read and what peers speak until they agree otherwise.
+pub struct Json;
+
+impl Codec for Json {
+    fn encode(&self, response: &Response) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(response)?)
+    }
+
+    fn decode(&self, message: &[u8]) -> Result<Response> {
+        Ok(serde_json::from_slice(message)?)
+    }
+}
+
+///Responses as MessagePack, with structures written as arrays of their fields. Used once peers agreed on `Capability::Binary`.
+pub struct Binary;
+
+impl Codec for Binary {
+    fn encode(&self, response: &Response) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec(response)?)
+    }
+
+    fn decode(&self, message: &[u8]) -> Result<Response> {
+        Ok(rmp_serde::from_slice(message)?)
+    }
+}
+
+///Optional features of the protocol.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum Capability {
+    ///Messages are compressed with DEFLATE.
+    Compression,
+    ///Messages are encoded in MessagePack instead of JSON.
+    Binary,
+    ///A capability of a newer build, which is never agreed on.
+    #[serde(other)]
+    Unknown,
+}
+
 ///Player-generated requests.
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Event {
     Player(Action),
     World(World),
+    Trade(Deal),
+    Economy(Economy),
+    Pack(Pack),
+    ///The research of a building is over, it can now be built.
+    Unlocked(BuildingID),
+    ///The stockpile of a resource is full, what is produced on top of it is lost.
+    StockpileFull(ResourceID),
+    ///The player logged in. Only ever sent by the server, never by a client.
+    Connected,
+    ///The player logged out. Only ever sent by the server, never by a client.
+    Disconnected,
+    ///Number of ticks simulated to make up for the time the player was offline, in reply to `Connected`.
+    CaughtUp(u64),
 }
 
 ///Events linked to authentification
@@ -52,8 +193,36 @@ pub enum Auth {
 ///Events affecting the world.
 #[derive(Debug, Deserialize, Serialize)]
 pub enum World {
+    ///Requesting the tile at a position.
     GetTile(Position),
-    Tile(Tile),
+    ///A tile and where it is, in reply to `GetTile`.
+    Tile { pos: Position, tile: Tile },
+    ///The `resource` deposit of the tile at `pos` ran out.
+    Exhausted { pos: Position, resource: ResourceID },
+}
+
+///Queries about the production of the player.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Economy {
+    ///Requesting how well the buildings of the player work.
+    GetReport,
+    ///The state of the economy, in reply to `GetReport`.
+    Report(Report),
+}
+
+///Sharing the buildings and resources of the game with clients.
+///
+///Right after `CaughtUp` the server sends the hash of its definitions. A client which doesn't have definitions
+///with this hash, because the server has mods or it has no data files at all, asks for them.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Pack {
+    ///The hash of the definitions of the game, see `Definitions::hash`.
+    ///It is written as 16 hexadecimal digits as JavaScript numbers can't hold all 64 bits.
+    Hash(#[serde(with = "in_hex")] u64),
+    ///Requesting the definitions of the game.
+    GetDefinitions,
+    ///The buildings and resources of the game, in reply to `GetDefinitions`.
+    Definitions(Definitions),
 }
 
 ///Events only affecting the player.
@@ -84,26 +253,83 @@ pub enum Action {
     Deposit { resource: ResourceID, amount: u32 },
     ///Remove resources from the player. Refer to `Player::withdraw`
     Withdraw { resource: ResourceID, amount: u32 },
+    ///Offer resources to another player. Refer to `Player::open_trade`
     //currently only supports simple trades
     Trade {
         from: Username,
         to: Username,
         offer: Offer,
     },
+    ///Accept an offer received from `from`. Refer to `Player::accept_trade`
+    AcceptTrade { from: Username, offer: Offer },
+    ///Turn down an offer received from `from`. Refer to `Player::refuse_trade`
+    RefuseTrade { from: Username, offer: Offer },
+    ///Withdraw an offer made to `to`. Refer to `Player::cancel_trade`
+    CancelTrade { to: Username, offer: Offer },
+    ///Start unlocking a building. Refer to `Player::research`
+    Research { building: BuildingID },
+}
+
+///Steps of a trade between two players.
+///
+///Each of them is sent to both parties so that their ledgers stay in sync.
+///In all variants `from` is the player who made the offer and `to` the one it was made to.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub enum Deal {
+    ///The offer was made. The offered resources are held in escrow until the trade is closed.
+    Opened {
+        from: Username,
+        to: Username,
+        offer: Offer,
+    },
+    ///Resources were exchanged.
+    Accepted {
+        from: Username,
+        to: Username,
+        offer: Offer,
+    },
+    ///`to` turned the offer down, the escrow went back to `from`.
+    Refused {
+        from: Username,
+        to: Username,
+        offer: Offer,
+    },
+    ///`from` withdrew the offer and got its escrow back.
+    Cancelled {
+        from: Username,
+        to: Username,
+        offer: Offer,
+    },
 }
 
 ///Errors resulting from Events.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum Exception {
     PlaceHolder,
     //related to player Action
     InsufficientResource(ResourceID),
     InsufficientSlot(BuildingID),
     InsufficientStockpile(ResourceID),
+    ///Not enough idle citizens to fill the jobs.
+    InsufficientCitizens,
     NotFound,
+    ///The building, or one of its prerequisites, hasn't been researched.
+    Locked(BuildingID),
+    ///Only one building can be researched at a time.
+    ResearchBusy,
+
+    //trades
+    ///The offer is empty, made to oneself or on behalf of someone else.
+    InvalidOffer,
+    ///The offer isn't open anymore, it may have been closed by the other party.
+    TradeNotFound,
+    ///Players can only trade with those they share a tile with.
+    NoContact(Username),
 
     //world exploration
     TileNotOwned(Position),
+    ///Players can only expand to tiles next to their lands.
+    Unreachable(Position),
 
     //auth errors
     ///The token used for logging in doesn't match used for registration of this user.
@@ -114,6 +340,29 @@ pub enum Exception {
     AlreadyRegistered,
     ///Generated when a client attempts to send a Response::Event without being logged in.
     LoggedOut,
+    ///The player didn't send anything for too long and has to log in again.
+    SessionExpired,
+    ///The player logged in from another connection, this one is closed.
+    Replaced,
+
+    //protocol
+    ///The client speaks another version of the protocol than the server, whose version is given.
+    ///Also sent to clients which don't start with `Hello`. The server closes the connection afterwards.
+    Incompatible(u32),
+}
+
+//(De)serializes a `u64` as a string of 16 hexadecimal digits.
+mod in_hex {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:016x}", value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        u64::from_str_radix(&text, 16).map_err(D::Error::custom)
+    }
 }
 
 impl fmt::Display for Exception {
@@ -127,3 +376,399 @@ impl fmt::Display for Exception {
 }
 
 impl Error for Exception {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buildings::Building;
+    use crate::pack::PackInfo;
+    use crate::trade::ResourceEntry;
+    use serde_json::{Map, Value};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    //Where the serialization of every variant in a version of the protocol is kept.
+    //The file of a new version is written by running the tests with UPDATE_FIXTURES set, existing ones are never changed.
+    fn fixture(version: u32) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/protocol")
+            .join(format!("v{}.json", version))
+    }
+
+    //Name of the variant, down to the innermost enum.
+    //Being exhaustive, it doesn't compile until new variants are named, which is a reminder to give them a sample.
+    fn variant(response: &Response) -> String {
+        let name = match response {
+            Response::Hello(_) => "Hello",
+            Response::Auth(auth) => match auth {
+                Auth::Login(..) => "Auth::Login",
+                Auth::Register(_) => "Auth::Register",
+                Auth::NewToken(_) => "Auth::NewToken",
+                Auth::Disconnect => "Auth::Disconnect",
+            },
+            Response::Event(event) => match event {
+                Event::Player(action) => match action {
+                    Action::Build { .. } => "Event::Player::Build",
+                    Action::Demolish { .. } => "Event::Player::Demolish",
+                    Action::Hire { .. } => "Event::Player::Hire",
+                    Action::Fire { .. } => "Event::Player::Fire",
+                    Action::Deposit { .. } => "Event::Player::Deposit",
+                    Action::Withdraw { .. } => "Event::Player::Withdraw",
+                    Action::Trade { .. } => "Event::Player::Trade",
+                    Action::AcceptTrade { .. } => "Event::Player::AcceptTrade",
+                    Action::RefuseTrade { .. } => "Event::Player::RefuseTrade",
+                    Action::CancelTrade { .. } => "Event::Player::CancelTrade",
+                    Action::Research { .. } => "Event::Player::Research",
+                },
+                Event::World(world) => match world {
+                    World::GetTile(_) => "Event::World::GetTile",
+                    World::Tile { .. } => "Event::World::Tile",
+                    World::Exhausted { .. } => "Event::World::Exhausted",
+                },
+                Event::Trade(deal) => match deal {
+                    Deal::Opened { .. } => "Event::Trade::Opened",
+                    Deal::Accepted { .. } => "Event::Trade::Accepted",
+                    Deal::Refused { .. } => "Event::Trade::Refused",
+                    Deal::Cancelled { .. } => "Event::Trade::Cancelled",
+                },
+                Event::Economy(economy) => match economy {
+                    Economy::GetReport => "Event::Economy::GetReport",
+                    Economy::Report(_) => "Event::Economy::Report",
+                },
+                Event::Pack(pack) => match pack {
+                    Pack::Hash(_) => "Event::Pack::Hash",
+                    Pack::GetDefinitions => "Event::Pack::GetDefinitions",
+                    Pack::Definitions(_) => "Event::Pack::Definitions",
+                },
+                Event::Unlocked(_) => "Event::Unlocked",
+                Event::StockpileFull(_) => "Event::StockpileFull",
+                Event::Connected => "Event::Connected",
+                Event::Disconnected => "Event::Disconnected",
+                Event::CaughtUp(_) => "Event::CaughtUp",
+            },
+            Response::Exception(exception) => match exception {
+                Exception::PlaceHolder => "Exception::PlaceHolder",
+                Exception::InsufficientResource(_) => "Exception::InsufficientResource",
+                Exception::InsufficientSlot(_) => "Exception::InsufficientSlot",
+                Exception::InsufficientStockpile(_) => "Exception::InsufficientStockpile",
+                Exception::InsufficientCitizens => "Exception::InsufficientCitizens",
+                Exception::NotFound => "Exception::NotFound",
+                Exception::Locked(_) => "Exception::Locked",
+                Exception::ResearchBusy => "Exception::ResearchBusy",
+                Exception::InvalidOffer => "Exception::InvalidOffer",
+                Exception::TradeNotFound => "Exception::TradeNotFound",
+                Exception::NoContact(_) => "Exception::NoContact",
+                Exception::TileNotOwned(_) => "Exception::TileNotOwned",
+                Exception::Unreachable(_) => "Exception::Unreachable",
+                Exception::InvalidToken => "Exception::InvalidToken",
+                Exception::Unregistered => "Exception::Unregistered",
+                Exception::AlreadyRegistered => "Exception::AlreadyRegistered",
+                Exception::LoggedOut => "Exception::LoggedOut",
+                Exception::SessionExpired => "Exception::SessionExpired",
+                Exception::Replaced => "Exception::Replaced",
+                Exception::Incompatible(_) => "Exception::Incompatible",
+            },
+        };
+        name.to_string()
+    }
+
+    //One response of each variant. Maps hold at most one entry so that they serialize the same every time.
+    fn samples() -> Vec<Response> {
+        let pos = Position { x: -2, y: 3 };
+        let building = BuildingID(1);
+        let resource = ResourceID(2);
+        let offer = || Offer {
+            offering: vec![ResourceEntry {
+                id: ResourceID(0),
+                amount: 10,
+            }],
+            requesting: vec![ResourceEntry {
+                id: ResourceID(1),
+                amount: 5,
+            }],
+        };
+        let name = |name: &str| name.to_string();
+        let mut tile = Tile::new();
+        tile.biome = name("forest");
+        tile.resources.amounts.insert(resource, 100);
+        tile.players.push(name("alice"));
+        let mut report = Report::default();
+        report.current.insert(building, 500_000);
+        report.bottlenecks.insert(building, resource);
+        report.cycles.push(vec![building]);
+        let mut buildings = HashMap::new();
+        buildings.insert(
+            building,
+            Building {
+                name: name("Lumberyard"),
+                extractor: true,
+                prerequisites: Vec::new(),
+                produced: vec![(ResourceID(0), 5)].into_iter().collect(),
+                consumed: HashMap::new(),
+                max_workers: 3,
+                construction_cost: vec![(ResourceID(0), 15)].into_iter().collect(),
+                storage: HashMap::new(),
+            },
+        );
+        let definitions = Definitions {
+            manifest: vec![PackInfo {
+                name: name("base"),
+                version: name("0.1.0"),
+            }],
+            buildings,
+            resources: vec![(ResourceID(0), name("wood"))].into_iter().collect(),
+        };
+
+        let mut samples = vec![
+            Response::Hello(Hello::new(vec![Capability::Compression])),
+            Response::Auth(Auth::Login(name("alice"), Token::fixed())),
+            Response::Auth(Auth::Register(name("alice"))),
+            Response::Auth(Auth::NewToken(Token::fixed())),
+            Response::Auth(Auth::Disconnect),
+        ];
+        let actions = vec![
+            Action::Build {
+                pos,
+                building,
+                amount: 2,
+            },
+            Action::Demolish {
+                pos,
+                building,
+                amount: 1,
+            },
+            Action::Hire {
+                building,
+                amount: 3,
+            },
+            Action::Fire {
+                building,
+                amount: 1,
+            },
+            Action::Deposit {
+                resource,
+                amount: 30,
+            },
+            Action::Withdraw {
+                resource,
+                amount: 20,
+            },
+            Action::Trade {
+                from: name("alice"),
+                to: name("bob"),
+                offer: offer(),
+            },
+            Action::AcceptTrade {
+                from: name("alice"),
+                offer: offer(),
+            },
+            Action::RefuseTrade {
+                from: name("alice"),
+                offer: offer(),
+            },
+            Action::CancelTrade {
+                to: name("bob"),
+                offer: offer(),
+            },
+            Action::Research { building },
+        ];
+        let deals = vec![
+            Deal::Opened {
+                from: name("alice"),
+                to: name("bob"),
+                offer: offer(),
+            },
+            Deal::Accepted {
+                from: name("alice"),
+                to: name("bob"),
+                offer: offer(),
+            },
+            Deal::Refused {
+                from: name("alice"),
+                to: name("bob"),
+                offer: offer(),
+            },
+            Deal::Cancelled {
+                from: name("alice"),
+                to: name("bob"),
+                offer: offer(),
+            },
+        ];
+        let events = actions
+            .into_iter()
+            .map(Event::Player)
+            .chain(vec![
+                Event::World(World::GetTile(pos)),
+                Event::World(World::Tile { pos, tile }),
+                Event::World(World::Exhausted { pos, resource }),
+            ])
+            .chain(deals.into_iter().map(Event::Trade))
+            .chain(vec![
+                Event::Economy(Economy::GetReport),
+                Event::Economy(Economy::Report(report)),
+                Event::Pack(Pack::Hash(0x0123_4567_89ab_cdef)),
+                Event::Pack(Pack::GetDefinitions),
+                Event::Pack(Pack::Definitions(definitions)),
+                Event::Unlocked(building),
+                Event::StockpileFull(resource),
+                Event::Connected,
+                Event::Disconnected,
+                Event::CaughtUp(1200),
+            ]);
+        samples.extend(events.map(Response::Event));
+        let exceptions = vec![
+            Exception::PlaceHolder,
+            Exception::InsufficientResource(resource),
+            Exception::InsufficientSlot(building),
+            Exception::InsufficientStockpile(resource),
+            Exception::InsufficientCitizens,
+            Exception::NotFound,
+            Exception::Locked(building),
+            Exception::ResearchBusy,
+            Exception::InvalidOffer,
+            Exception::TradeNotFound,
+            Exception::NoContact(name("bob")),
+            Exception::TileNotOwned(pos),
+            Exception::Unreachable(pos),
+            Exception::InvalidToken,
+            Exception::Unregistered,
+            Exception::AlreadyRegistered,
+            Exception::LoggedOut,
+            Exception::SessionExpired,
+            Exception::Replaced,
+            Exception::Incompatible(PROTOCOL_VERSION),
+        ];
+        samples.extend(exceptions.into_iter().map(Response::Exception));
+        samples
+    }
+
+    #[test]
+    fn test_fixtures() {
+        let mut serialized = Map::new();
+        for sample in samples() {
+            let name = variant(&sample);
+            let value = serde_json::to_value(&sample).unwrap();
+            assert!(
+                serialized.insert(name.clone(), value).is_none(),
+                "{} has two samples",
+                name
+            );
+        }
+        let path = fixture(PROTOCOL_VERSION);
+        if !path.exists() && std::env::var_os("UPDATE_FIXTURES").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let json = serde_json::to_string_pretty(&serialized).unwrap();
+            fs::write(&path, json + "\n").unwrap();
+        }
+        let file = fs::read(&path).unwrap_or_else(|_| {
+            panic!(
+                "{} is missing, run the tests with UPDATE_FIXTURES set to write it",
+                path.display()
+            )
+        });
+        let fixtures: Map<String, Value> = serde_json::from_slice(&file).unwrap();
+        for (name, fixture) in &fixtures {
+            //what older builds of this version send can still be read
+            let response: Response = serde_json::from_value(fixture.clone())
+                .unwrap_or_else(|error| panic!("{} can't be read anymore: {}", name, error));
+            assert_eq!(&variant(&response), name);
+            assert_eq!(
+                serialized.get(name),
+                Some(fixture),
+                "{} changed, PROTOCOL_VERSION must be bumped",
+                name
+            );
+        }
+        for name in serialized.keys() {
+            assert!(
+                fixtures.contains_key(name),
+                "{} isn't in the fixtures of version {}, PROTOCOL_VERSION must be bumped",
+                name,
+                PROTOCOL_VERSION
+            );
+        }
+    }
+
+    #[test]
+    fn test_older_fixtures() {
+        for version in 1..PROTOCOL_VERSION {
+            let file = fs::read(fixture(version)).unwrap();
+            let fixtures: Map<String, Value> = serde_json::from_slice(&file).unwrap();
+            for name in ["Hello", "Exception::Incompatible"].iter() {
+                let fixture = &fixtures[*name];
+                let response: Response = serde_json::from_value(fixture.clone())
+                    .unwrap_or_else(|error| panic!("{} of version {}: {}", name, version, error));
+                assert_eq!(&serde_json::to_value(&response).unwrap(), fixture);
+                //clients of older versions are told which one the server speaks
+                if let Response::Hello(hello) = response {
+                    assert!(matches!(
+                        hello.reply(&[]),
+                        Err(Exception::Incompatible(PROTOCOL_VERSION))
+                    ));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_codecs() {
+        for sample in samples() {
+            let expected = serde_json::to_value(&sample).unwrap();
+            let json = Json.encode(&sample).unwrap();
+            let binary = Binary.encode(&sample).unwrap();
+            for (codec, message) in [(&Json as &dyn Codec, &json), (&Binary, &binary)].iter() {
+                let decoded = codec.decode(message).unwrap();
+                assert_eq!(serde_json::to_value(&decoded).unwrap(), expected);
+            }
+            assert!(
+                binary.len() < json.len(),
+                "{} is bigger in binary",
+                variant(&sample)
+            );
+        }
+        assert!(Binary.decode(b"{}").is_err());
+
+        let hello = Hello::new(vec![Capability::Binary]);
+        let json = serde_json::to_vec(&Response::Exception(Exception::LoggedOut)).unwrap();
+        assert!(hello.codec().decode(&json).is_err());
+        assert!(Hello::new(vec![]).codec().decode(&json).is_ok());
+    }
+
+    #[test]
+    fn test_hello() {
+        //the handshake must be readable whatever the version
+        let hello: Response =
+            serde_json::from_str(r#"{"Hello":{"version":0,"capabilities":["Teleportation"]}}"#)
+                .unwrap();
+        let hello = match hello {
+            Response::Hello(hello) => hello,
+            _ => panic!("not a hello"),
+        };
+        assert_eq!(hello.capabilities, vec![Capability::Unknown]);
+        assert!(matches!(
+            hello.reply(&[Capability::Compression]),
+            Err(Exception::Incompatible(PROTOCOL_VERSION))
+        ));
+
+        let hello = Hello::new(vec![
+            Capability::Binary,
+            Capability::Unknown,
+            Capability::Compression,
+            Capability::Compression,
+        ]);
+        let reply = hello
+            .reply(&[Capability::Compression, Capability::Unknown])
+            .unwrap();
+        assert_eq!(reply, Hello::new(vec![Capability::Compression]));
+        assert!(reply.supports(Capability::Compression));
+        assert!(!reply.supports(Capability::Binary));
+        //capabilities may be left out
+        let bare: Hello =
+            serde_json::from_str(&format!(r#"{{"version":{}}}"#, PROTOCOL_VERSION)).unwrap();
+        assert_eq!(
+            bare.reply(&[Capability::Compression]).unwrap().capabilities,
+            vec![]
+        );
+    }
+}
@@ -0,0 +1,456 @@
+//! Reading and writing `GameData` to the disk.
+//!
+//! A save holds the `version` of its layout along with the game `data`. It is written either in JSON,
+//! which is handy for debugging, or in the more compact MessagePack. The format is chosen from the extension of the file.
+//! Saves made by older versions of the game are upgraded when they are read, one version at a time,
+//! by the migrations of `MIGRATIONS`. Changing the layout of `GameData` (or of anything it contains)
+//! in a way serde can't read from older saves means bumping `VERSION` and adding a migration.
+use crate::GameData;
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Version of the layout of the saves written by this version of the game.
+pub const VERSION: u32 = 2;
+
+/// How a save is encoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Human readable, saves have the `json` extension.
+    Json,
+    /// Compact, saves have the `msgpack` extension.
+    Binary,
+}
+
+impl Format {
+    /// The format of saves with the `extension` extension, if any.
+    pub fn from_extension(extension: &str) -> Option<Format> {
+        match extension {
+            "json" => Some(Format::Json),
+            "msgpack" => Some(Format::Binary),
+            _ => None,
+        }
+    }
+
+    /// The format of the save at `path`, decided by its extension.
+    pub fn of<P: AsRef<Path>>(path: P) -> Result<Format> {
+        let path = path.as_ref();
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(Format::from_extension)
+            .ok_or_else(|| anyhow!("{} isn't a json or msgpack save", path.display()))
+    }
+
+    /// Extension of saves in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Binary => "msgpack",
+        }
+    }
+}
+
+//Upgrades the data of a save from the version it's at to the next one.
+type Migration = fn(Value) -> Result<Value>;
+
+//The migration at index N turns data of version N into data of version N + 1.
+const MIGRATIONS: [Migration; VERSION as usize] = [from_v0, from_v1];
+
+#[derive(Serialize)]
+struct Header<'a> {
+    version: u32,
+    data: &'a GameData,
+}
+
+/// A save file found by `list`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SaveInfo {
+    /// Name of the save, which is the name of the file without its extension.
+    pub name: String,
+    pub path: PathBuf,
+    /// When the file was last written to.
+    pub modified: SystemTime,
+}
+
+/// Where and how often a running game saves itself, see `Game::set_autosave`.
+#[derive(Debug, Clone)]
+pub struct Autosave {
+    /// Directory the saves are written to.
+    pub dir: PathBuf,
+    /// Time between two saves.
+    pub interval: Duration,
+    /// Number of saves kept in `dir`, older ones are deleted. See `rotate`.
+    pub keep: usize,
+    pub format: Format,
+}
+
+/// Writes `data` to the file at `path`, in the format of its extension.
+///
+/// The save is first written to a temporary file which then replaces the one at `path`.
+/// This way a crash while saving can't leave a corrupted save behind.
+pub fn write<P: AsRef<Path>>(path: P, data: &GameData) -> Result<()> {
+    let path = path.as_ref();
+    let header = Header {
+        version: VERSION,
+        data,
+    };
+    let content = match Format::of(path)? {
+        Format::Json => serde_json::to_vec(&header)?,
+        //with the names of the fields the save can be read as JSON when it has to be migrated
+        Format::Binary => rmp_serde::to_vec_named(&header)?,
+    };
+    let tmp = path.with_extension("tmp");
+    let mut file =
+        File::create(&tmp).with_context(|| format!("couldn't create {}", tmp.display()))?;
+    file.write_all(&content)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("couldn't write {}", path.display()))?;
+    Ok(())
+}
+
+/// Reads the save at `path`, migrating it from older versions if needed.
+///
+/// # Errors
+/// This fails if the file can't be read, if it isn't a save or if it was made by a newer version of the game.
+pub fn read<P: AsRef<Path>>(path: P) -> Result<GameData> {
+    let path = path.as_ref();
+    let file = fs::read(path).with_context(|| format!("couldn't read {}", path.display()))?;
+    let save: Value = match Format::of(path)? {
+        Format::Json => serde_json::from_slice(&file)?,
+        Format::Binary => to_json(rmpv::decode::read_value(&mut file.as_slice())?)?,
+    };
+    Ok(serde_json::from_value(migrate(save)?)?)
+}
+
+/// Rewrites the save at `from` to `to`, whose extensions decide the formats.
+///
+/// The save is migrated to the current version along the way.
+pub fn convert<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
+    write(to, &read(from)?)
+}
+
+//MessagePack is turned into JSON so that all saves are migrated the same way.
+//Only the keys of maps differ as MessagePack allows non-string ones. Those of saves are numbers, like `ResourceID`.
+fn to_json(value: rmpv::Value) -> Result<Value> {
+    use rmpv::Value as Pack;
+    Ok(match value {
+        Pack::Nil => Value::Null,
+        Pack::Boolean(b) => Value::Bool(b),
+        Pack::Integer(int) => match (int.as_u64(), int.as_i64()) {
+            (Some(u), _) => json!(u),
+            (_, Some(i)) => json!(i),
+            _ => return Err(anyhow!("{} is out of range", int)),
+        },
+        Pack::F32(f) => json!(f),
+        Pack::F64(f) => json!(f),
+        Pack::String(s) => Value::String(
+            s.into_str()
+                .ok_or_else(|| anyhow!("the save holds invalid UTF-8"))?,
+        ),
+        Pack::Binary(bytes) => json!(bytes),
+        Pack::Array(items) => Value::Array(items.into_iter().map(to_json).collect::<Result<_>>()?),
+        Pack::Map(entries) => {
+            let mut map = Map::new();
+            for (key, value) in entries {
+                let key = match key {
+                    Pack::String(s) => s
+                        .into_str()
+                        .ok_or_else(|| anyhow!("the save holds invalid UTF-8"))?,
+                    Pack::Integer(int) => int.to_string(),
+                    other => return Err(anyhow!("{} can't be the key of a map", other)),
+                };
+                map.insert(key, to_json(value)?);
+            }
+            Value::Object(map)
+        }
+        Pack::Ext(..) => return Err(anyhow!("the save holds MessagePack extensions")),
+    })
+}
+
+//Brings the content of a save file to the current version, returning its data.
+fn migrate(save: Value) -> Result<Value> {
+    let (version, mut data) = match save {
+        Value::Object(mut header) if header.contains_key("version") => {
+            let version = header
+                .get("version")
+                .and_then(Value::as_u64)
+                .ok_or_else(|| anyhow!("the version of the save isn't a number"))?;
+            let data = header
+                .remove("data")
+                .ok_or_else(|| anyhow!("the save has no data"))?;
+            (version as usize, data)
+        }
+        //saves made before they had a header were just the data
+        data => (0, data),
+    };
+    if version > VERSION as usize {
+        return Err(anyhow!(
+            "the save is of version {} but only versions up to {} can be read",
+            version,
+            VERSION
+        ));
+    }
+    for migration in MIGRATIONS[version..].iter() {
+        data = migration(data)?;
+    }
+    Ok(data)
+}
+
+//Maps keyed by positions were JSON objects, they are now lists of couples.
+//As positions can't be object keys these could only ever be empty in the saves that were successfully written.
+//The first saves also predate seeded worlds and the ring spiral of `PosGenerator`. Their spiral panicked
+//before yielding any position so it starts over, and their world was empty so any seed does.
+fn from_v0(mut data: Value) -> Result<Value> {
+    fn pairs(map: Option<&mut Value>) {
+        if let Some(map) = map {
+            if map.as_object().is_some_and(Map::is_empty) {
+                *map = json!([]);
+            }
+        }
+    }
+
+    let game = data
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("the data of the save isn't an object"))?;
+    game.entry("seed").or_insert(json!(0));
+    if let Some(pos_gen) = game.get_mut("pos_gen") {
+        if pos_gen.get("step").is_some() {
+            *pos_gen = json!({"topology": "Square", "ring": 0, "index": 0});
+        }
+    }
+
+    pairs(data.get_mut("world"));
+    if let Some(players) = data.get_mut("players").and_then(Value::as_object_mut) {
+        for player in players.values_mut() {
+            if let Some(buildings) = player.get_mut("buildings").and_then(Value::as_object_mut) {
+                for building in buildings.values_mut() {
+                    pairs(building.get_mut("tiles"));
+                }
+            }
+        }
+    }
+    Ok(data)
+}
+
+//Saves now tell which data packs the game needs. Games used to be played with the base pack alone.
+fn from_v1(mut data: Value) -> Result<Value> {
+    data.as_object_mut()
+        .ok_or_else(|| anyhow!("the data of the save isn't an object"))?
+        .insert(
+            "packs".to_string(),
+            json!([{"name": "base", "version": "0.1.0"}]),
+        );
+    Ok(data)
+}
+
+/// All saves in the `dir` directory, from the oldest to the most recent.
+pub fn list<P: AsRef<Path>>(dir: P) -> Result<Vec<SaveInfo>> {
+    let mut saves = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if Format::of(&path).is_err() {
+            continue;
+        }
+        let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        saves.push(SaveInfo {
+            name,
+            modified: fs::metadata(&path)?.modified()?,
+            path,
+        });
+    }
+    saves.sort_by(|a, b| {
+        a.modified
+            .cmp(&b.modified)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    Ok(saves)
+}
+
+/// Deletes the oldest saves of the `dir` directory so that only the `keep` most recent ones remain.
+///
+/// Only the saves named after the time they were made, like those of `Game::save`, are rotated.
+/// Others, like the converted or renamed ones, are left alone. Returns the paths of the deleted saves.
+pub fn rotate<P: AsRef<Path>>(dir: P, keep: usize) -> Result<Vec<PathBuf>> {
+    let saves: Vec<SaveInfo> = list(dir)?
+        .into_iter()
+        .filter(|save| !save.name.is_empty() && save.name.bytes().all(|c| c.is_ascii_digit()))
+        .collect();
+    let old = saves.len().saturating_sub(keep);
+    let mut deleted = Vec::new();
+    for save in saves.into_iter().take(old) {
+        fs::remove_file(&save.path)?;
+        deleted.push(save.path);
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buildings::{AllBuildings, BuildingID, DependencyGraph};
+    use crate::pos::{PosGenerator, Topology};
+    use crate::resources::ResourceID;
+    use crate::test_utils::{base, buildings, dir, player};
+    use crate::tile::{Position, Tile};
+
+    fn game(all: &AllBuildings) -> GameData {
+        let mut player = player(all);
+        player.hire(BuildingID(0), 2).unwrap();
+        let mut tile = Tile::new();
+        tile.resources.amounts.insert(ResourceID(0), 100);
+        tile.players.push("alice".to_string());
+        GameData {
+            world: vec![(Position { x: 0, y: 0 }, tile)].into_iter().collect(),
+            players: vec![("alice".to_string(), player)].into_iter().collect(),
+            pos_gen: PosGenerator::new(Topology::Square),
+            seed: 7,
+            tick: 12,
+            time: 0,
+            packs: Vec::new(),
+        }
+    }
+
+    fn wood(data: &GameData) -> u32 {
+        data.players["alice"].resources[&ResourceID(0)].current()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let all = buildings();
+        let graph = DependencyGraph::new(&all);
+        let dir = dir("round-trip");
+        for format in [Format::Json, Format::Binary].iter() {
+            let mut original = game(&all);
+            let path = dir.join("save").with_extension(format.extension());
+            write(&path, &original).unwrap();
+            let mut loaded = read(&path).unwrap();
+            assert_eq!((loaded.seed, loaded.tick), (7, 12));
+            assert_eq!(
+                loaded.world[&Position { x: 0, y: 0 }].players,
+                vec!["alice"]
+            );
+
+            //the generator isn't saved, it must be rebuilt so that the empire keeps producing the same
+            for data in [&mut original, &mut loaded].iter_mut() {
+                let (players, world) = (&mut data.players, &mut data.world);
+                let player = players.get_mut("alice").unwrap();
+                player.generate(&all, &graph, world, 0);
+            }
+            assert_eq!(wood(&loaded), wood(&original));
+            assert!(wood(&loaded) > 0);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_convert() {
+        let all = buildings();
+        let dir = dir("convert");
+        let (json, binary) = (dir.join("save.json"), dir.join("save.msgpack"));
+        write(&json, &game(&all)).unwrap();
+        convert(&json, &binary).unwrap();
+        fs::remove_file(&json).unwrap();
+        convert(&binary, &json).unwrap();
+        let text = fs::read_to_string(&json).unwrap();
+        let sizes = (
+            fs::metadata(&json).unwrap().len(),
+            fs::metadata(&binary).unwrap().len(),
+        );
+        let loaded = read(&json).unwrap();
+        assert!(convert(&json, dir.join("save.txt")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+
+        //positions are written as strings in JSON
+        assert!(text.contains(r#""world":{"0,0":"#));
+        assert!(sizes.1 < sizes.0);
+        assert_eq!(loaded.tick, 12);
+        assert!(loaded.players.contains_key("alice"));
+    }
+
+    #[test]
+    fn test_migration() {
+        let all = buildings();
+        let mut data = game(&all);
+        data.world.clear();
+        //saves used to be the data alone, with objects for maps keyed by positions
+        let mut legacy = serde_json::to_value(&data).unwrap();
+        legacy["world"] = json!({});
+        for building in legacy["players"]["alice"]["buildings"]
+            .as_object_mut()
+            .unwrap()
+            .values_mut()
+        {
+            building["tiles"] = json!({});
+        }
+        let dir = dir("migration");
+        let path = dir.join("legacy.json");
+        fs::write(&path, serde_json::to_vec(&legacy).unwrap()).unwrap();
+        let loaded = read(&path).unwrap();
+
+        fs::write(
+            &path,
+            json!({"version": VERSION + 1, "data": {}}).to_string(),
+        )
+        .unwrap();
+        assert!(read(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(loaded.world.is_empty());
+        assert_eq!(loaded.players.len(), 1);
+        assert_eq!(loaded.packs[0].name, "base");
+    }
+
+    #[test]
+    fn test_baseline_save() {
+        //written by the first version of the game, before saves had a header
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/saves/v0.json");
+        let data = read(&path).unwrap();
+        assert!(data.world.is_empty() && data.players.is_empty());
+        assert_eq!(data.seed, 0);
+        assert_eq!(data.pos_gen.topology(), Topology::Square);
+        assert_eq!(data.pos_gen.state(), (0, 0));
+
+        let mut game = crate::Game::load(&path, crate::DataPack::load(base()).unwrap()).unwrap();
+        let capital = game.add_player("alice".to_string()).unwrap().capital;
+        assert_eq!(capital, Some(Position { x: 0, y: 0 }));
+    }
+
+    #[test]
+    fn test_list() {
+        let all = buildings();
+        let data = game(&all);
+        let dir = dir("list");
+        write(dir.join("1.json"), &data).unwrap();
+        write(dir.join("2.json"), &data).unwrap();
+        fs::write(dir.join("notes.txt"), "not a save").unwrap();
+        let names: Vec<String> = list(&dir).unwrap().into_iter().map(|s| s.name).collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(names, vec!["1", "2"]);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let all = buildings();
+        let data = game(&all);
+        let dir = dir("rotate");
+        //saves which weren't made by the game are the oldest
+        write(dir.join("backup.json"), &data).unwrap();
+        write(dir.join("1-converted.msgpack"), &data).unwrap();
+        for name in 0..4 {
+            write(dir.join(format!("{}.json", name)), &data).unwrap();
+        }
+        let deleted = rotate(&dir, 3).unwrap();
+        let mut names: Vec<String> = list(&dir).unwrap().into_iter().map(|s| s.name).collect();
+        names.sort();
+        assert!(rotate(&dir, 3).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(deleted, vec![dir.join("0.json")]);
+        assert_eq!(names, vec!["1", "1-converted", "2", "3", "backup"]);
+    }
+}
@@ -0,0 +1,36 @@
+//! Fixtures shared by the tests of several modules.
+use crate::buildings::AllBuildings;
+use crate::player::Player;
+use crate::tile::Position;
+use std::path::{Path, PathBuf};
+
+/// A lumberyard, an iron mine requiring it and a warehouse for wood.
+pub fn buildings() -> AllBuildings {
+    serde_json::from_str(
+        r#"{
+            "0": ["Lumberyard", true, [], {"0": 5}, {}, 3, {"0": 15}],
+            "1": ["Iron Mine", true, [0], {"1": 2}, {}, 5, {"2": 150, "0": 30}],
+            "2": ["Warehouse", false, [], {}, {}, 0, {}, {"0": 100}]
+        }"#,
+    )
+    .unwrap()
+}
+
+/// A player with the starting buildings of `buildings` on the origin.
+pub fn player(buildings: &AllBuildings) -> Player {
+    let mut player = Player::new();
+    player.settle(Position { x: 0, y: 0 }, buildings);
+    player
+}
+
+/// A directory of its own for each test as they run concurrently.
+pub fn dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("idle-crawler-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The base data pack shipped with the game.
+pub fn base() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../data")
+}
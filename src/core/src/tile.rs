@@ -12,44 +12,159 @@ pub struct Position {
     pub y: i32,
 }
 
+impl Position {
+    ///Coordinates of the map are between `-LIMIT` and `LIMIT`. Positions sent by clients beyond that are refused,
+    ///which keeps computations on positions, like `Topology::line`, from overflowing.
+    pub const LIMIT: i32 = 1 << 20;
+
+    ///True if the position is part of the map, see `LIMIT`.
+    pub fn in_range(&self) -> bool {
+        self.x.unsigned_abs() <= Position::LIMIT as u32
+            && self.y.unsigned_abs() <= Position::LIMIT as u32
+    }
+
+    ///Cube coordinates of a position of a hexagonal map, which stores axial coordinates.
+    pub fn cube(&self) -> (i64, i64, i64) {
+        let (x, y) = (self.x as i64, self.y as i64);
+        (x, y, -x - y)
+    }
+
+    ///The position `offset` away from this one, if it has coordinates.
+    pub fn checked_add(&self, offset: &Position) -> Option<Position> {
+        Some(Position {
+            x: self.x.checked_add(offset.x)?,
+            y: self.y.checked_add(offset.y)?,
+        })
+    }
+}
+
+/// (De)serializes maps keyed by `Position`. Use it with `#[serde(with = "by_position")]`.
+///
+/// Positions aren't strings so they can't be the keys of a JSON object. Human readable formats
+/// get an object whose keys are the positions written as `"x,y"` while others get a list of `(position, value)` couples.
+/// Both are accepted when deserializing.
+//Entries are sorted so that saving the same game always gives the same file.
+pub(crate) mod by_position {
+    use super::Position;
+    use serde::de::{self, MapAccess, SeqAccess, Visitor};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::marker::PhantomData;
+
+    pub fn serialize<V: Serialize, S: Serializer>(
+        map: &HashMap<Position, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut entries: Vec<(&Position, &V)> = map.iter().collect();
+        entries.sort_by_key(|(pos, _)| (pos.x, pos.y));
+        if serializer.is_human_readable() {
+            serializer.collect_map(
+                entries
+                    .into_iter()
+                    .map(|(pos, value)| (format!("{},{}", pos.x, pos.y), value)),
+            )
+        } else {
+            serializer.collect_seq(entries)
+        }
+    }
+
+    pub fn deserialize<'de, V: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<Position, V>, D::Error> {
+        deserializer.deserialize_any(PositionMap(PhantomData))
+    }
+
+    fn parse(key: &str) -> Option<Position> {
+        let mut coordinates = key.split(',').map(|c| c.trim().parse::<i32>());
+        match (coordinates.next(), coordinates.next(), coordinates.next()) {
+            (Some(Ok(x)), Some(Ok(y)), None) => Some(Position { x, y }),
+            _ => None,
+        }
+    }
+
+    struct PositionMap<V>(PhantomData<V>);
+
+    impl<'de, V: Deserialize<'de>> Visitor<'de> for PositionMap<V> {
+        type Value = HashMap<Position, V>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str(
+                "a map keyed by \"x,y\" positions or a list of (position, value) couples",
+            )
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut map = HashMap::new();
+            while let Some((pos, value)) = seq.next_element::<(Position, V)>()? {
+                map.insert(pos, value);
+            }
+            Ok(map)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+            let mut map = HashMap::new();
+            while let Some(key) = access.next_key::<String>()? {
+                let pos = parse(&key).ok_or_else(|| {
+                    de::Error::invalid_value(de::Unexpected::Str(&key), &"a \"x,y\" position")
+                })?;
+                map.insert(pos, access.next_value()?);
+            }
+            Ok(map)
+        }
+    }
+}
+
 ///Space allocated to a resource in a Tile.
 ///
 ///Each tile has resources, each of them can have multiple "spots" on which you can place extractor buildings of the correspongding type.
 ///A Slots keeps track of how many of this Tile's "spots" are used and free.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Slots {
     pub used: u32,
     pub total: u32,
 }
 
 ///Tracks players resources and expansion options.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TileResouces {
     //HashMap<"building_name", [u8;2]>
     pub slots: HashMap<BuildingID, Slots>,
     //HashMap<"resource_name", u32>
-    amounts: HashMap<ResourceID, u32>,
+    pub amounts: HashMap<ResourceID, u32>,
+    //amounts the tile was generated with. Renewable resources grow back up to them.
+    #[serde(default)]
+    pub capacity: HashMap<ResourceID, u32>,
 }
 
 ///The basemost spacial unit. Contains raw resources ready to be extracted.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Tile {
+    ///Name of the biome the tile belongs to. Empty if the tile wasn't generated.
+    #[serde(default)]
+    pub biome: String,
     pub resources: TileResouces,
     //Vec<"username">
     pub players: Vec<String>,
 }
 
 impl Tile {
+    ///Creates a barren tile. See `WorldGenerator::tile` for tiles with resources.
     pub fn new() -> Tile {
-        //currently hardcoded for testing purposes
-        let mut slots = HashMap::new();
-        slots.insert("iron_mine", [2, 5]);
         Tile {
+            biome: String::new(),
             resources: TileResouces {
                 slots: HashMap::new(),
                 amounts: HashMap::new(),
+                capacity: HashMap::new(),
             },
             players: Vec::new(),
         }
     }
 }
+
+impl Default for Tile {
+    fn default() -> Self {
+        Tile::new()
+    }
+}
@@ -1,9 +1,10 @@
 use crate::player::Username;
+use crate::response::Exception;
 use crate::ResourceID;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Serialize, Deserialize, Hash, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Clone)]
 pub struct ResourceEntry {
     pub id: ResourceID,
     pub amount: u32,
@@ -17,16 +18,45 @@ pub struct ResourceEntry {
 ///
 /// Note that either of these can be empty. This allows for donations.
 /// However special care should be used so that no empty offers are made.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Hash)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Hash, Clone)]
 pub struct Offer {
     pub offering: Vec<ResourceEntry>,
     pub requesting: Vec<ResourceEntry>,
 }
 
+impl Offer {
+    /// True if nothing is exchanged by this offer.
+    pub fn is_empty(&self) -> bool {
+        self.offering.iter().all(|entry| entry.amount == 0)
+            && self.requesting.iter().all(|entry| entry.amount == 0)
+    }
+}
+
+/// Sums the amounts of entries sharing the same resource.
+///
+/// Offers are free to list a resource more than once, so checks against stockpiles
+/// must be made on the totals.
+///
+/// # Errors
+/// `Exception::InvalidOffer` if a total doesn't fit in a `u32`.
+pub(crate) fn tally(entries: &[ResourceEntry]) -> Result<HashMap<ResourceID, u32>, Exception> {
+    let mut totals = HashMap::new();
+    for entry in entries {
+        let total = totals.entry(entry.id).or_insert(0u32);
+        *total = total
+            .checked_add(entry.amount)
+            .ok_or(Exception::InvalidOffer)?;
+    }
+    Ok(totals)
+}
+
 /// Trade register
 ///
 /// The word "ledger" refers to an account books. As such it is used to keep track of an entity's
 /// open trades. This can be either an alliance, or more commonly, a plasyer.
+///
+/// Every open trade is registered twice: in the `outbound` map of the party that made the offer
+/// and in the `inbound` map of the party it was made to.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Ledger {
     pub inbound: HashMap<Username, Vec<Offer>>,
@@ -0,0 +1,246 @@
+use crate::buildings::BuildingID;
+use crate::resources::ResourceID;
+use crate::tile::{Position, Slots, Tile, TileResouces};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A resource deposit that may appear on the tiles of a biome.
+///
+/// `building` is the extractor that can be placed on the patch and `resource` the raw
+/// resource it holds. Ranges are inclusive.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Patch {
+    pub building: BuildingID,
+    pub resource: ResourceID,
+    /// Odds (in percents) for a tile of the biome to have this patch.
+    pub chance: u8,
+    /// Number of extractors that can be placed on the patch.
+    pub slots: (u32, u32),
+    /// Amount of `resource` held by the patch.
+    pub amount: (u32, u32),
+}
+
+/// A kind of land and the patches it can hold.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Biome {
+    pub name: String,
+    /// Range of the noise (out of 256) covered by the biome. The upper bound is excluded.
+    pub noise: (u16, u16),
+    pub patches: Vec<Patch>,
+}
+
+/// Rules used to generate the world, read from the game data.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WorldRules {
+    /// Width, in tiles, of the noise cells. The bigger it is, the larger biomes are.
+    pub scale: u32,
+    pub biomes: Vec<Biome>,
+    /// Amount under which a deposit starts yielding less to extractors.
+    pub low_deposit: u32,
+    /// Resources which grow back on their own and the amount they regain each tick.
+    #[serde(default)]
+    pub renewable: HashMap<ResourceID, u32>,
+    /// Resources a player pays to expand its lands to a new tile.
+    #[serde(default)]
+    pub claim_cost: HashMap<ResourceID, u32>,
+}
+
+/// Procedural world generator
+///
+/// Creates the tiles of the world from a seed and the `WorldRules`.
+/// A tile only depends on the seed, the rules and its position: the same seed always yields the same map,
+/// no matter the order in which tiles are generated. This is why `GameData` only needs to store the seed
+/// for any server to be able to regenerate the world.
+///
+/// All the math is done on integers so that generation doesn't differ between machines.
+#[derive(Debug)]
+pub struct WorldGenerator {
+    seed: u64,
+    rules: WorldRules,
+}
+
+impl WorldGenerator {
+    pub fn new(seed: u64, rules: WorldRules) -> WorldGenerator {
+        WorldGenerator { seed, rules }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    //A random number for each (position, salt) couple.
+    //This is the splitmix64 finalizer applied to the seeded coordinates.
+    fn hash(&self, x: i32, y: i32, salt: u64) -> u64 {
+        let coords = ((x as u32 as u64) << 32) | y as u32 as u64;
+        let mut z = self.seed
+            ^ coords.wrapping_mul(0x9E37_79B9_7F4A_7C15)
+            ^ salt.wrapping_mul(0xD1B5_4A32_D192_ED03);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Smooth value noise in `0..256` for a position.
+    ///
+    /// Random values are picked at the corners of cells of `WorldRules::scale` tiles
+    /// and interpolated in-between so that neighbouring tiles are alike.
+    pub fn noise(&self, pos: &Position) -> u16 {
+        let scale = self.rules.scale.max(1) as i32;
+        let (cx, cy) = (pos.x.div_euclid(scale), pos.y.div_euclid(scale));
+        let (fx, fy) = (
+            pos.x.rem_euclid(scale) as u64,
+            pos.y.rem_euclid(scale) as u64,
+        );
+        let s = scale as u64;
+        let corner = |dx: i32, dy: i32| self.hash(cx + dx, cy + dy, 0) % 256;
+        let top = corner(0, 0) * (s - fx) + corner(1, 0) * fx;
+        let bottom = corner(0, 1) * (s - fx) + corner(1, 1) * fx;
+        ((top * (s - fy) + bottom * fy) / (s * s)) as u16
+    }
+
+    /// The biome a position belongs to, if any covers its noise.
+    pub fn biome(&self, pos: &Position) -> Option<&Biome> {
+        let noise = self.noise(pos);
+        self.rules
+            .biomes
+            .iter()
+            .find(|biome| biome.noise.0 <= noise && noise < biome.noise.1)
+    }
+
+    /// Generates the tile at `pos`.
+    pub fn tile(&self, pos: &Position) -> Tile {
+        let mut tile = Tile::new();
+        let biome = match self.biome(pos) {
+            Some(biome) => biome,
+            None => return tile,
+        };
+        tile.biome = biome.name.clone();
+        let TileResouces {
+            slots,
+            amounts,
+            capacity,
+        } = &mut tile.resources;
+        for (idx, patch) in biome.patches.iter().enumerate() {
+            let roll = self.hash(pos.x, pos.y, idx as u64 + 1);
+            if roll % 100 >= patch.chance as u64 {
+                continue;
+            }
+            let pick = |(min, max): (u32, u32), bits: u64| {
+                min + (bits % (max.saturating_sub(min) as u64 + 1)) as u32
+            };
+            let total = pick(patch.slots, roll >> 8);
+            let amount = pick(patch.amount, roll >> 24);
+            slots
+                .entry(patch.building)
+                .or_insert(Slots { used: 0, total: 0 })
+                .total += total;
+            *amounts.entry(patch.resource).or_insert(0) += amount;
+            *capacity.entry(patch.resource).or_insert(0) += amount;
+        }
+        tile
+    }
+
+    /// Regrows the renewable resources of `tile` toward the amounts it was generated with.
+    pub fn regrow(&self, tile: &mut Tile) {
+        self.regrow_for(tile, 1);
+    }
+
+    /// Regrows `tile` as much as it would in `ticks` ticks.
+    pub fn regrow_for(&self, tile: &mut Tile, ticks: u64) {
+        for (resource, rate) in self.rules.renewable.iter() {
+            if let Some(capacity) = tile.resources.capacity.get(resource) {
+                let amount = tile.resources.amounts.entry(*resource).or_insert(0);
+                let grown = (*rate as u64).saturating_mul(ticks);
+                *amount = (*amount as u64).saturating_add(grown).min(*capacity as u64) as u32;
+            }
+        }
+    }
+
+    pub fn rules(&self) -> &WorldRules {
+        &self.rules
+    }
+
+    /// Generates all tiles at `positions`.
+    pub fn tiles<'a, I: IntoIterator<Item = &'a Position>>(
+        &self,
+        positions: I,
+    ) -> HashMap<Position, Tile> {
+        positions
+            .into_iter()
+            .map(|pos| (*pos, self.tile(pos)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> WorldRules {
+        serde_json::from_str(
+            r#"{
+                "scale": 4,
+                "low_deposit": 50,
+                "renewable": {"0": 5},
+                "biomes": [
+                    {"name": "plains", "noise": [0, 128], "patches": [
+                        {"building": 0, "resource": 0, "chance": 100, "slots": [1, 3], "amount": [100, 200]}
+                    ]},
+                    {"name": "hills", "noise": [128, 256], "patches": [
+                        {"building": 1, "resource": 1, "chance": 50, "slots": [2, 2], "amount": [300, 300]}
+                    ]}
+                ]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_same_seed_same_world() {
+        let (a, b) = (
+            WorldGenerator::new(42, rules()),
+            WorldGenerator::new(42, rules()),
+        );
+        for x in -10..10 {
+            for y in -10..10 {
+                let pos = Position { x, y };
+                let (ta, tb) = (a.tile(&pos), b.tile(&pos));
+                assert_eq!(ta.biome, tb.biome);
+                assert_eq!(ta.resources.amounts, tb.resources.amounts);
+            }
+        }
+    }
+
+    #[test]
+    fn test_patches_follow_rules() {
+        let gen = WorldGenerator::new(7, rules());
+        for x in -10..10 {
+            let tile = gen.tile(&Position { x, y: 3 });
+            if tile.biome == "plains" {
+                let slots = &tile.resources.slots[&BuildingID(0)];
+                assert!((1..=3).contains(&slots.total));
+                assert!((100..=200).contains(&tile.resources.amounts[&ResourceID(0)]));
+            } else if let Some(slots) = tile.resources.slots.get(&BuildingID(1)) {
+                assert_eq!(slots.total, 2);
+            }
+        }
+    }
+
+    #[test]
+    fn test_regrow() {
+        let gen = WorldGenerator::new(3, rules());
+        let mut tile = Tile::new();
+        tile.resources.capacity.insert(ResourceID(0), 12);
+        tile.resources.capacity.insert(ResourceID(1), 12);
+        tile.resources.amounts.insert(ResourceID(0), 0);
+        tile.resources.amounts.insert(ResourceID(1), 0);
+        for _ in 0..3 {
+            gen.regrow(&mut tile);
+        }
+        assert_eq!(tile.resources.amounts[&ResourceID(0)], 12);
+        assert_eq!(tile.resources.amounts[&ResourceID(1)], 0);
+        tile.resources.amounts.insert(ResourceID(0), 0);
+        gen.regrow_for(&mut tile, 3);
+        assert_eq!(tile.resources.amounts[&ResourceID(0)], 12);
+    }
+}
@@ -1,92 +1,169 @@
-use anyhow::Result;
-use core::response::{Action, Auth, Event, Exception, Response, Token};
+mod transport;
+
+use anyhow::{anyhow, Result};
+use core::accounts::{Accounts, Sessions};
+use core::response::{
+    Action, Auth, Capability, Event, Exception, Response, Token, PROTOCOL_VERSION,
+};
+use core::save::{self, Autosave, Format};
 use core::Username;
-use core::{BuildingID, Game, ResourceID};
+use core::{BuildingID, DataPack, Game, ResourceID, Topology, DATA_DIR};
 use serde_json;
 use std::collections::HashMap;
 use std::error::Error;
-use std::fs::write;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::PathBuf;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::{env, io};
 use tokio;
-use tokio::net::UdpSocket;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::UnboundedReceiver;
+use transport::{Incoming, Kind, Session, IDLE_TIMEOUT, TICK};
 
-//How should I determine the size of the buffer? By calculating the size of the largest event (Build)
-const BUFFER_SIZE: usize = 1024;
 const USERS_PATH: &str = "accounts.json";
-
-type Accounts = HashMap<Username, Token>;
+const SAVES_DIR: &str = "saves";
+//seconds between two autosaves
+const AUTOSAVE_INTERVAL: u64 = 300;
+//number of autosaves kept around
+const AUTOSAVE_KEEP: usize = 5;
+//what the server agrees on when a client says hello
+const CAPABILITIES: &[Capability] = &[Capability::Compression, Capability::Binary];
 
 struct Server {
-    game: (Sender<(Username, Event)>, Receiver<(Username, Exception)>),
+    game: (Sender<(Username, Event)>, Receiver<(Username, Response)>),
     accounts: Accounts,
-    socket: UdpSocket,
-    //None means not authentificated
-    clients: HashMap<SocketAddr, Option<Username>>,
-    buf: Vec<u8>,
+    incoming: UnboundedReceiver<Incoming>,
+    sessions: HashMap<SocketAddr, Session>,
+    logins: Sessions<SocketAddr>,
 }
 
 impl Server {
-    async fn poll(&mut self) -> Result<(SocketAddr, Response), io::Error> {
-        //writing the data when available
-        let (read_to, client_addr) = self.socket.recv_from(&mut self.buf).await?;
-        //making sure the data is valid json
-        let response: Response = serde_json::from_slice(&self.buf[..read_to])?;
-        println!("Received JSON: {:?}", response);
-        //self.clients.entry(client_addr).or_insert(Client::new()).pending = Some(reponse);
-        Ok((client_addr, response))
+    //Waits for something to happen on the transport, for at most `TICK`, and returns the response it brought if any.
+    async fn poll(&mut self) -> Result<Option<(SocketAddr, Response)>, io::Error> {
+        let incoming = match tokio::time::timeout(TICK, self.incoming.recv()).await {
+            Ok(Some(incoming)) => incoming,
+            Ok(None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "the transport stopped",
+                ))
+            }
+            Err(_) => return Ok(None),
+        };
+        match incoming {
+            Incoming::Opened(addr, link) => {
+                self.sessions.insert(addr, Session::new(link));
+            }
+            Incoming::Message(addr, message) => {
+                let session = match self.sessions.get_mut(&addr) {
+                    Some(session) => session,
+                    None => return Ok(None),
+                };
+                session.last_seen = Instant::now();
+                self.logins.touch(&addr, session.last_seen);
+                match session.decode(&message) {
+                    Ok(response) => {
+                        println!("Received: {:?}", response);
+                        return Ok(Some((addr, response)));
+                    }
+                    //this is likely an outdated client which doesn't know about Hello
+                    Err(error) if session.hello.is_none() => {
+                        println!("Unreadable greeting from {}: {}", addr, error);
+                        self.refuse(addr, Exception::Incompatible(PROTOCOL_VERSION))
+                            .await?;
+                    }
+                    Err(error) => println!("Dropped a message from {}: {}", addr, error),
+                }
+            }
+            Incoming::Closed(addr) => {
+                self.sessions.remove(&addr);
+                if let Some(username) = self.logins.logout(&addr) {
+                    self.send_to_game(username, Event::Disconnected).await?;
+                }
+            }
+        }
+        Ok(None)
     }
 
     async fn update_once(&mut self) -> Result<(), io::Error> {
-        let (addr, response) = self.poll().await?;
+        if let Some((addr, response)) = self.poll().await? {
+            self.handle(addr, response).await?;
+        }
+        //relaying what the game has to say to the players, whether they sent an event or not
+        let pending: Vec<(Username, Response)> = self.game.1.try_iter().collect();
+        for (username, response) in pending {
+            //players who aren't connected will miss the response
+            if let Some(addr) = self.logins.peer(&username) {
+                self.dispatch(&response, addr).await?;
+            }
+        }
+        let now = Instant::now();
+        for (addr, username) in self.logins.expire(now) {
+            self.dispatch(&Response::Exception(Exception::SessionExpired), addr)
+                .await?;
+            self.send_to_game(username, Event::Disconnected).await?;
+        }
+        //peers which don't log in are let go once they have been quiet for a while
+        let logins = &self.logins;
+        self.sessions.retain(|addr, session| {
+            logins.user(addr).is_ok() || now.duration_since(session.last_seen) < IDLE_TIMEOUT
+        });
+        Ok(())
+    }
+
+    async fn handle(&mut self, addr: SocketAddr, response: Response) -> Result<(), io::Error> {
+        let greeted = self
+            .sessions
+            .get(&addr)
+            .is_some_and(|session| session.hello.is_some());
         match response {
+            Response::Hello(hello) if !greeted => match hello.reply(CAPABILITIES) {
+                Ok(reply) => {
+                    //the reply is sent before the capabilities are in use
+                    self.dispatch(&Response::Hello(reply.clone()), addr).await?;
+                    if let Some(session) = self.sessions.get_mut(&addr) {
+                        session.hello = Some(reply);
+                    }
+                }
+                Err(exception) => self.refuse(addr, exception).await?,
+            },
+            _ if !greeted => {
+                self.refuse(addr, Exception::Incompatible(PROTOCOL_VERSION))
+                    .await?
+            }
+            Response::Hello(_) => {
+                self.dispatch(&Response::Exception(Exception::PlaceHolder), addr)
+                    .await?
+            }
             Response::Auth(auth) => match auth {
-                Auth::Disconnect => if self.clients.remove(&addr).is_none() {
-                        self.socket
-                            .send_to(
-                                serde_json::to_string(&Exception::LoggedOut)
-                                    .unwrap()
-                                    .as_bytes(),
-                                addr,
-                            )
-                            .await?;} else {
-                                self.dispatch(&Response::Exception(Exception::LoggedOut), addr).await?;
-                            }
+                Auth::Disconnect => {
+                    self.dispatch(&Response::Exception(Exception::LoggedOut), addr).await?;
+                    //dropping the session closes the connection once the reply is sent
+                    self.sessions.remove(&addr);
+                    if let Some(username) = self.logins.logout(&addr) {
+                        //the game stops simulating the empire until the player comes back
+                        self.send_to_game(username, Event::Disconnected).await?;
+                    }
+                }
                 Auth::Login(username, token) => self.login(addr, username, &token).await?,
                 Auth::Register(username) => self.register(addr, username).await?,
-                Auth::NewToken(_) => panic!("The server should never receive NewToken! It is supposed to send it when registration succeeds."),
+                //only ever sent by the server, when registration succeeds
+                Auth::NewToken(_) => self.dispatch(&Response::Exception(Exception::PlaceHolder), addr).await?,
             }
             Response::Event(event) => {
                 //we check for auth first because all other events require a logged user
-                if self.clients.contains_key(&addr){
-                    match self.clients.get(&addr).unwrap().clone() {
-                        Some(username) => self.send_to_game(username, event).await?,
-                        None => self.dispatch(&Response::Exception(Exception::LoggedOut), addr).await?
-                    }
+                match self.logins.user(&addr).cloned() {
+                    //sessions are only ever reported by the server
+                    Ok(_) if matches!(event, Event::Connected | Event::Disconnected) => self.dispatch(&Response::Exception(Exception::PlaceHolder), addr).await?,
+                    Ok(username) => self.send_to_game(username, event).await?,
+                    Err(exception) => self.dispatch(&Response::Exception(exception), addr).await?
                 }
             }
             Response::Exception(_) => (),
         }
-        //we should only have at most one exception waiting here.
-        if let Ok(exception) = self.game.1.try_recv() {
-            let crt_addr = *self
-                .clients
-                .iter()
-                .find(|user| {
-                    if let Some(username) = user.1 {
-                        *username == exception.0
-                    } else {
-                        false
-                    }
-                })
-                .unwrap()
-                .0;
-            self.dispatch(&Response::Exception(exception.1), crt_addr)
-                .await?;
-        }
         Ok(())
     }
 
@@ -104,98 +181,470 @@ impl Server {
     }
 
     async fn dispatch(&mut self, response: &Response, addr: SocketAddr) -> Result<(), io::Error> {
-        self.socket
-            .send_to(serde_json::to_string(response).unwrap().as_bytes(), addr)
-            .await?;
-        //this is for dev only!
-        save_accounts(USERS_PATH, &self.accounts).await;
+        if let Some(session) = self.sessions.get(&addr) {
+            session.send(response);
+        }
         Ok(())
     }
 
-    async fn register(&mut self, addr: SocketAddr, username: Username) -> Result<(), io::Error> {
-        if self.accounts.contains_key(&username) {
-            self.dispatch(&Response::Exception(Exception::AlreadyRegistered), addr)
-                .await?;
-        }
-        let token = Token::new();
-        self.dispatch(&Response::Auth(Auth::NewToken(token)), addr)
-            .await?;
+    //Turns down a peer the server can't talk with, closing the connection once the exception is sent.
+    async fn refuse(&mut self, addr: SocketAddr, exception: Exception) -> Result<(), io::Error> {
+        self.dispatch(&Response::Exception(exception), addr).await?;
+        self.sessions.remove(&addr);
         Ok(())
     }
 
+    async fn register(&mut self, addr: SocketAddr, username: Username) -> Result<(), io::Error> {
+        let response = match self.accounts.register(&username) {
+            Ok(token) => Response::Auth(Auth::NewToken(token)),
+            Err(error) => Response::Exception(error.downcast().unwrap_or_else(|error| {
+                println!("Couldn't register {}: {:#}", username, error);
+                Exception::PlaceHolder
+            })),
+        };
+        self.dispatch(&response, addr).await
+    }
+
     async fn login(
         &mut self,
         addr: SocketAddr,
         username: Username,
         token: &Token,
     ) -> Result<(), io::Error> {
-        match self.accounts.get(&username) {
-            Some(r_token) => {
-                if r_token == token {
-                    *self.clients.get_mut(&addr).unwrap() = Some(username)
-                } else {
-                    self.dispatch(&Response::Exception(Exception::InvalidToken), addr)
-                        .await?;
-                }
+        if let Err(exception) = self.accounts.verify(&username, token) {
+            return self.dispatch(&Response::Exception(exception), addr).await;
+        }
+        //logging in as someone else logs out whoever was logged in on this peer
+        if let Some(previous) = self.logins.logout(&addr) {
+            if previous != username {
+                self.send_to_game(previous, Event::Disconnected).await?;
             }
-            None => {
-                self.dispatch(&Response::Exception(Exception::Unregistered), addr)
-                    .await?
+        }
+        if let Some(replaced) = self.logins.login(addr, username.clone(), Instant::now()) {
+            self.dispatch(&Response::Exception(Exception::Replaced), replaced)
+                .await?;
+            self.sessions.remove(&replaced);
+        }
+        //the game replies with how long the player was away
+        self.send_to_game(username, Event::Connected).await
+    }
+}
+
+/// Command line options of the server.
+///
+/// `server [port] [--transport <udp|tcp|ws>] [--pack <dir>]... [--topology <square|hex>] [--load <save>] [--saves <dir>] [--autosave <seconds>] [--keep <count>] [--format <json|msgpack>] [--offline-cap <seconds>]`
+///
+/// `server convert <from> <to>` rewrites a save in the format given by the extension of `to` instead.
+struct Options {
+    port: String,
+    transport: Kind,
+    //overlays stacked on the base data pack, in order
+    packs: Vec<PathBuf>,
+    //the shape of the map of a new world, a save keeps its own
+    topology: Topology,
+    //the save to resume, a new world is created otherwise
+    load: Option<PathBuf>,
+    saves: PathBuf,
+    interval: Duration,
+    keep: usize,
+    format: Format,
+    //longest absence made up for, the game's default otherwise
+    offline_cap: Option<Duration>,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options> {
+        let mut options = Options {
+            port: "6142".to_string(),
+            transport: Kind::Udp,
+            packs: Vec::new(),
+            topology: Topology::Square,
+            load: None,
+            saves: PathBuf::from(SAVES_DIR),
+            interval: Duration::from_secs(AUTOSAVE_INTERVAL),
+            keep: AUTOSAVE_KEEP,
+            format: Format::Binary,
+            offline_cap: None,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--transport" => options.transport = value(&mut args, &arg)?.parse()?,
+                "--pack" => options.packs.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--topology" => options.topology = value(&mut args, &arg)?.parse()?,
+                "--load" => options.load = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--saves" => options.saves = PathBuf::from(value(&mut args, &arg)?),
+                "--autosave" => {
+                    options.interval = Duration::from_secs(value(&mut args, &arg)?.parse()?)
+                }
+                "--keep" => options.keep = value(&mut args, &arg)?.parse()?,
+                "--offline-cap" => {
+                    options.offline_cap =
+                        Some(Duration::from_secs(value(&mut args, &arg)?.parse()?))
+                }
+                "--format" => {
+                    let format = value(&mut args, &arg)?;
+                    options.format = Format::from_extension(&format)
+                        .ok_or_else(|| anyhow!("unknown save format {}", format))?
+                }
+                _ => options.port = arg,
             }
         }
-        Ok(())
+        Ok(options)
     }
 }
 
-async fn load_accounts<A: AsRef<Path>>(path: A) -> Accounts {
-    let file = std::fs::read(path).expect("coudln't read accounts file");
-    serde_json::from_slice(&file).expect("couldn't serialize accounts JSON")
+//The value following an option.
+fn value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String> {
+    args.next()
+        .ok_or_else(|| anyhow!("{} must be followed by a value", option))
 }
 
-async fn save_accounts<A: AsRef<Path>>(path: A, accounts: &Accounts) -> Result<()> {
-    Ok(write(path, serde_json::to_string(accounts)?)?)
+//Resolves once the server is asked to stop, by Ctrl+C or SIGTERM.
+#[cfg(unix)]
+async fn shutdown() -> io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown() -> io::Result<()> {
+    tokio::signal::ctrl_c().await
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let addr = "127.0.0.1:".to_owned() + &env::args().nth(1).unwrap_or_else(|| "6142".to_string());
+    if env::args().nth(1).as_deref() == Some("convert") {
+        let mut args = env::args().skip(2);
+        let from = value(&mut args, "convert")?;
+        let to = value(&mut args, "convert")?;
+        save::convert(&from, &to)?;
+        println!("Converted {} to {}", from, to);
+        return Ok(());
+    }
+    let options = Options::parse(env::args().skip(1))?;
+    let pack = DataPack::layered(DATA_DIR, &options.packs)?;
+    let addr = "127.0.0.1:".to_owned() + &options.port;
 
-    let socket = UdpSocket::bind(&addr).await?;
+    let incoming = transport::listen(options.transport, &addr).await?;
 
     let (tx1, rx1) = channel();
     let (tx2, rx2) = channel();
     let server = Server {
         game: (tx1, rx2),
-        accounts: load_accounts(USERS_PATH).await,
-        socket,
-        clients: HashMap::new(),
-        buf: vec![0; BUFFER_SIZE],
+        accounts: Accounts::load(USERS_PATH)?,
+        incoming,
+        sessions: HashMap::new(),
+        logins: Sessions::new(),
     };
-    println!("Listening on: {}", server.socket.local_addr()?);
-
-    //this is a DEV ONLY section that will need re-work
-    let mut game = Game::new(0);
-    println!(
-        "Resources: {:?}\nBuildings: {:?}",
-        game.get_resources(),
-        game.get_buildings()
-    );
-    let p = game.add_player(String::from("Toude"))?;
-    println!(
-        "An event in JSON:\n {:?}\n",
-        serde_json::to_string(&Response::Event(Event::Player(Action::Hire {
-            building: BuildingID(0),
-            amount: 3
-        })))
-    );
-    p.deposit(ResourceID(0), 30)?;
-    p.hire(BuildingID(0), 2)?;
-    p.hire(BuildingID(1), 1)?;
-    println!("Toude {:?}", p);
-    thread::spawn(move || game.run(1, tx2, rx1));
-    server.run().await?;
+
+    let mut game = match options.load.as_ref() {
+        Some(save) => {
+            println!("Resuming {}", save.display());
+            Game::load(save, pack)?
+        }
+        None => {
+            //this is a DEV ONLY section that will need re-work
+            let mut game = Game::new(0, 0, options.topology, pack);
+            println!(
+                "Resources: {:?}\nBuildings: {:?}",
+                game.get_resources(),
+                game.get_buildings()
+            );
+            let p = game.add_player(String::from("Toude"))?;
+            println!(
+                "An event in JSON:\n {:?}\n",
+                serde_json::to_string(&Response::Event(Event::Player(Action::Hire {
+                    building: BuildingID(0),
+                    amount: 3
+                })))
+            );
+            p.deposit(ResourceID(0), 30)?;
+            p.hire(BuildingID(0), 2)?;
+            p.hire(BuildingID(1), 1)?;
+            println!("Toude {:?}", p);
+            game
+        }
+    };
+    game.set_autosave(Autosave {
+        dir: options.saves,
+        interval: options.interval,
+        keep: options.keep,
+        format: options.format,
+    });
+    if let Some(cap) = options.offline_cap {
+        game.set_offline_cap(cap);
+    }
+    //the game only stops on its own when it fails, its error is reported once it is joined
+    let (stopped, game_stopped) = tokio::sync::oneshot::channel();
+    let handle = thread::spawn(move || {
+        let result = game.run(1, tx2, rx1);
+        let _ = stopped.send(());
+        result
+    });
 
     //running server
+    let outcome = tokio::select! {
+        result = server.run() => result,
+        result = shutdown() => result.map_err(anyhow::Error::from),
+        _ = game_stopped => Ok(()),
+    };
+    //the server is dropped by now, along with its channels, so the game saves and stops
+    println!("Waiting for the game to save");
+    handle.join().expect("the game thread panicked")?;
+    outcome?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::accounts::SESSION_TIMEOUT;
+    use core::response::{Codec, Hello, Json};
+    use std::future::Future;
+    use tokio::sync::mpsc::{self, UnboundedSender};
+    use transport::Link;
+
+    //`tokio::test` can't be used as the `core` crate of the game hides the standard one
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Runtime::new().unwrap().block_on(future)
+    }
+
+    //A server whose transport and game are played by the test.
+    struct Harness {
+        server: Server,
+        transport: UnboundedSender<Incoming>,
+        //what the server sends to the game
+        game: Receiver<(Username, Event)>,
+        //keeps the channel from the game open
+        _replies: Sender<(Username, Response)>,
+        path: PathBuf,
+    }
+
+    impl Harness {
+        //a server with no accounts, whose sessions expire after `timeout`
+        fn new(name: &str, timeout: Duration) -> Harness {
+            let path = env::temp_dir().join(format!(
+                "idle-crawler-server-{}-{}.json",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            let (transport, incoming) = mpsc::unbounded_channel();
+            let (events, game) = channel();
+            let (replies, responses) = channel();
+            Harness {
+                server: Server {
+                    game: (events, responses),
+                    accounts: Accounts::load(&path).unwrap(),
+                    incoming,
+                    sessions: HashMap::new(),
+                    logins: Sessions::with_timeout(timeout),
+                },
+                transport,
+                game,
+                _replies: replies,
+                path,
+            }
+        }
+
+        //a peer on `port` which said hello
+        async fn connect(&mut self, port: u16) -> Peer {
+            let (link, outgoing) = mpsc::unbounded_channel();
+            let peer = Peer {
+                addr: SocketAddr::from(([127, 0, 0, 1], port)),
+                outgoing,
+            };
+            let opened = Incoming::Opened(peer.addr, Link(link));
+            assert!(self.transport.send(opened).is_ok());
+            self.server.update_once().await.unwrap();
+            self.send(&peer, Response::Hello(Hello::new(Vec::new())))
+                .await;
+            peer
+        }
+
+        async fn send(&mut self, peer: &Peer, response: Response) {
+            let message = Incoming::Message(peer.addr, Json.encode(&response).unwrap());
+            assert!(self.transport.send(message).is_ok());
+            self.server.update_once().await.unwrap();
+        }
+
+        fn events(&self) -> Vec<(Username, Event)> {
+            self.game.try_iter().collect()
+        }
+    }
+
+    impl Drop for Harness {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    struct Peer {
+        addr: SocketAddr,
+        outgoing: mpsc::UnboundedReceiver<Vec<u8>>,
+    }
+
+    impl Peer {
+        //what the server sent since the last call, the handshake aside
+        fn received(&mut self) -> Vec<Response> {
+            let mut received = Vec::new();
+            while let Ok(message) = self.outgoing.try_recv() {
+                match Json.decode(&message).unwrap() {
+                    Response::Hello(_) => (),
+                    response => received.push(response),
+                }
+            }
+            received
+        }
+
+        //the exception the server sent since the last call
+        fn exception(&mut self) -> Exception {
+            match self.received().as_slice() {
+                [Response::Exception(exception)] => exception.clone(),
+                other => panic!("expected an exception, got {:?}", other),
+            }
+        }
+    }
+
+    fn login(username: &str, token: Token) -> Response {
+        Response::Auth(Auth::Login(username.to_string(), token))
+    }
+
+    fn register(username: &str) -> Response {
+        Response::Auth(Auth::Register(username.to_string()))
+    }
+
+    #[test]
+    fn test_options() {
+        let args = |args: &[&str]| Options::parse(args.iter().map(|arg| arg.to_string()));
+        assert_eq!(args(&[]).unwrap().topology, Topology::Square);
+        let options = args(&["--topology", "hex", "7000"]).unwrap();
+        assert_eq!(options.topology, Topology::Hexagonal);
+        assert_eq!(options.port, "7000");
+        assert!(args(&["--topology", "triangle"]).is_err());
+        assert!(args(&["--topology"]).is_err());
+    }
+
+    #[test]
+    fn test_login() {
+        block_on(async {
+            let mut harness = Harness::new("login", SESSION_TIMEOUT);
+            let mut peer = harness.connect(1).await;
+            let event = || {
+                Response::Event(Event::Player(Action::Hire {
+                    building: BuildingID(0),
+                    amount: 1,
+                }))
+            };
+
+            harness.send(&peer, event()).await;
+            assert!(matches!(peer.exception(), Exception::LoggedOut));
+            harness.send(&peer, login("alice", Token::random())).await;
+            assert!(matches!(peer.exception(), Exception::Unregistered));
+
+            harness.send(&peer, register("alice")).await;
+            let token = match peer.received().pop() {
+                Some(Response::Auth(Auth::NewToken(token))) => token,
+                other => panic!("expected a token, got {:?}", other),
+            };
+            //tokens are only given out by the server
+            harness
+                .send(&peer, Response::Auth(Auth::NewToken(token)))
+                .await;
+            assert!(matches!(peer.exception(), Exception::PlaceHolder));
+            harness.send(&peer, register("alice")).await;
+            assert!(matches!(peer.exception(), Exception::AlreadyRegistered));
+            harness.send(&peer, login("alice", Token::random())).await;
+            assert!(matches!(peer.exception(), Exception::InvalidToken));
+            assert!(harness.events().is_empty());
+
+            harness.send(&peer, login("alice", token)).await;
+            harness.send(&peer, event()).await;
+            //only the server reports sessions
+            harness.send(&peer, Response::Event(Event::Connected)).await;
+            assert!(matches!(peer.exception(), Exception::PlaceHolder));
+            assert!(matches!(
+                harness.events().as_slice(),
+                [
+                    (_, Event::Connected),
+                    (_, Event::Player(Action::Hire { .. }))
+                ]
+            ));
+
+            harness.send(&peer, Response::Auth(Auth::Disconnect)).await;
+            assert!(matches!(peer.exception(), Exception::LoggedOut));
+            assert!(matches!(
+                harness.events().as_slice(),
+                [(_, Event::Disconnected)]
+            ));
+            //the connection is closed
+            assert!(!harness.server.sessions.contains_key(&peer.addr));
+        });
+    }
+
+    #[test]
+    fn test_outdated_accounts() {
+        block_on(async {
+            let mut harness = Harness::new("outdated", SESSION_TIMEOUT);
+            //the accounts of a server which stored plain numbers
+            std::fs::write(&harness.path, r#"{"alice":3421545}"#).unwrap();
+            harness.server.accounts = Accounts::load(&harness.path).unwrap();
+            let mut peer = harness.connect(1).await;
+            harness.send(&peer, register("alice")).await;
+            let token = match peer.received().pop() {
+                Some(Response::Auth(Auth::NewToken(token))) => token,
+                other => panic!("expected a token, got {:?}", other),
+            };
+            harness.send(&peer, login("alice", token)).await;
+            assert!(matches!(
+                harness.events().as_slice(),
+                [(_, Event::Connected)]
+            ));
+        });
+    }
+
+    #[test]
+    fn test_replaced() {
+        block_on(async {
+            let mut harness = Harness::new("replaced", SESSION_TIMEOUT);
+            let mut first = harness.connect(1).await;
+            let token = harness.server.accounts.register("alice").unwrap();
+            harness.send(&first, login("alice", token)).await;
+
+            let mut second = harness.connect(2).await;
+            harness.send(&second, login("alice", token)).await;
+            assert!(matches!(first.exception(), Exception::Replaced));
+            assert!(second.received().is_empty());
+            assert!(!harness.server.sessions.contains_key(&first.addr));
+            //the player stays connected to the game, from the second peer
+            assert!(matches!(
+                harness.events().as_slice(),
+                [(_, Event::Connected), (_, Event::Connected)]
+            ));
+            assert_eq!(harness.server.logins.peer("alice"), Some(second.addr));
+        });
+    }
+
+    #[test]
+    fn test_expired() {
+        block_on(async {
+            //sessions expire as soon as the server looks at them
+            let mut harness = Harness::new("expired", Duration::from_secs(0));
+            let mut peer = harness.connect(1).await;
+            let token = harness.server.accounts.register("alice").unwrap();
+            harness.send(&peer, login("alice", token)).await;
+            assert!(matches!(peer.exception(), Exception::SessionExpired));
+            assert!(matches!(
+                harness.events().as_slice(),
+                [(_, Event::Connected), (_, Event::Disconnected)]
+            ));
+
+            harness.send(&peer, Response::Event(Event::Connected)).await;
+            assert!(matches!(peer.exception(), Exception::LoggedOut));
+        });
+    }
+}
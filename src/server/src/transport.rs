@@ -0,0 +1,418 @@
+//! The ways clients can reach the server.
+//!
+//! Whatever the transport, it turns the connections of clients into a stream of `Incoming` messages and gives
+//! each peer a `Link` the server writes its messages to. A message is a serialized `Response`.
+//! - UDP goes through the reliable layer of `core::net`
+//! - TCP prefixes each message by its length, as a big endian `u32`
+//! - WebSocket sends each message in a frame of its own, as text when it's valid UTF-8
+//!
+//! Messages are encoded with the codec the peer and the server agreed on when saying hello, then compressed with
+//! DEFLATE if they agreed on `Capability::Compression`.
+use anyhow::{anyhow, Result};
+use core::net::{Channel, MAX_DATAGRAM, MAX_MESSAGE};
+use core::response::{Codec, Hello, Json, Response};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc::{self, error::TryRecvError, UnboundedReceiver, UnboundedSender};
+use tokio_tungstenite::tungstenite::Message;
+
+/// How long the UDP transport waits for a datagram before sending what the server wrote and what was lost.
+pub const TICK: Duration = Duration::from_millis(50);
+/// Peers which the server let go of are forgotten after this long without a word.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Transport the server listens on, chosen with `--transport`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Udp,
+    Tcp,
+    WebSocket,
+}
+
+impl FromStr for Kind {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Kind> {
+        match name {
+            "udp" => Ok(Kind::Udp),
+            "tcp" => Ok(Kind::Tcp),
+            "ws" => Ok(Kind::WebSocket),
+            _ => Err(anyhow!("unknown transport {}", name)),
+        }
+    }
+}
+
+/// What happened on the transport.
+pub enum Incoming {
+    /// A peer connected, messages are sent to it through the link.
+    Opened(SocketAddr, Link),
+    Message(SocketAddr, Vec<u8>),
+    /// The peer left or can't be reached anymore.
+    Closed(SocketAddr),
+}
+
+/// Where the messages of the server to a peer go. Dropping it closes the connection.
+pub struct Link(pub(crate) UnboundedSender<Vec<u8>>);
+
+/// A peer of the server. Who is logged in on it is tracked by `core::accounts::Sessions`.
+pub struct Session {
+    pub link: Link,
+    //when the peer last sent a message
+    pub last_seen: Instant,
+    //None until the peer said hello
+    pub hello: Option<Hello>,
+}
+
+impl Session {
+    pub fn new(link: Link) -> Session {
+        Session {
+            link,
+            last_seen: Instant::now(),
+            hello: None,
+        }
+    }
+
+    /// Sends `response` to the peer. It is lost if the connection was closed or if it can't be encoded.
+    pub fn send(&self, response: &Response) {
+        let message = match self.hello.as_ref() {
+            Some(hello) => hello.encode(response),
+            None => Json.encode(response),
+        };
+        match message {
+            Ok(message) => {
+                let _ = self.link.0.send(message);
+            }
+            Err(error) => println!("Dropped a response which couldn't be encoded: {:#}", error),
+        }
+    }
+
+    /// Reads a message of the peer.
+    pub fn decode(&self, message: &[u8]) -> Result<Response> {
+        match self.hello.as_ref() {
+            Some(hello) => hello.decode(message),
+            None => Json.decode(message),
+        }
+    }
+}
+
+/// Listens on `addr` with the `kind` transport, reporting what happens on the returned receiver.
+pub async fn listen(kind: Kind, addr: &str) -> Result<UnboundedReceiver<Incoming>> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    match kind {
+        Kind::Udp => {
+            let socket = UdpSocket::bind(addr).await?;
+            println!("Listening on: udp://{}", socket.local_addr()?);
+            tokio::spawn(report(udp(socket, sender)));
+        }
+        Kind::Tcp | Kind::WebSocket => {
+            let listener = TcpListener::bind(addr).await?;
+            println!("Listening on: {:?}://{}", kind, listener.local_addr()?);
+            tokio::spawn(report(tcp(listener, kind, sender)));
+        }
+    }
+    Ok(receiver)
+}
+
+//Runs a transport, telling why if it stops.
+async fn report<F: Future<Output = io::Result<()>>>(transport: F) {
+    if let Err(error) = transport.await {
+        println!("The transport stopped: {}", error);
+    }
+}
+
+//A peer of the UDP transport.
+struct Peer {
+    channel: Channel,
+    //None once the server let go of the peer
+    outgoing: Option<UnboundedReceiver<Vec<u8>>>,
+}
+
+async fn udp(mut socket: UdpSocket, incoming: UnboundedSender<Incoming>) -> io::Result<()> {
+    let mut peers: HashMap<SocketAddr, Peer> = HashMap::new();
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        if let Ok(received) = tokio::time::timeout(TICK, socket.recv_from(&mut buf)).await {
+            let (read_to, addr) = received?;
+            let peer = peers.entry(addr).or_insert_with(|| Peer {
+                channel: Channel::new(),
+                outgoing: None,
+            });
+            match peer.channel.receive(&buf[..read_to], Instant::now()) {
+                Ok(messages) if !messages.is_empty() => {
+                    if peer.outgoing.is_none() {
+                        let (link, outgoing) = mpsc::unbounded_channel();
+                        peer.outgoing = Some(outgoing);
+                        send(&incoming, Incoming::Opened(addr, Link(link)))?;
+                    }
+                    for message in messages {
+                        send(&incoming, Incoming::Message(addr, message))?;
+                    }
+                }
+                Ok(_) => (),
+                Err(error) => println!("Dropped a datagram from {}: {}", addr, error),
+            }
+        }
+
+        let now = Instant::now();
+        let mut forgotten = Vec::new();
+        for (addr, peer) in peers.iter_mut() {
+            while let Some(outgoing) = peer.outgoing.as_mut() {
+                match outgoing.try_recv() {
+                    Ok(message) => {
+                        if let Err(error) = peer.channel.send(&message, now) {
+                            println!("Couldn't send a message to {}: {}", addr, error);
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Closed) => peer.outgoing = None,
+                }
+            }
+            let mut lost = peer.channel.tick(now).err().map(|error| error.to_string());
+            if lost.is_none() {
+                for datagram in peer.channel.datagrams() {
+                    //only this peer is let go of, as when its address can't be reached
+                    if let Err(error) = socket.send_to(&datagram, addr).await {
+                        lost = Some(error.to_string());
+                        break;
+                    }
+                }
+            }
+            if let Some(error) = lost {
+                println!("Lost contact with {}: {}", addr, error);
+                if peer.outgoing.is_some() {
+                    send(&incoming, Incoming::Closed(*addr))?;
+                }
+                forgotten.push(*addr);
+                continue;
+            }
+            //the channel is kept a while so that late datagrams of the peer aren't mistaken for a new stream
+            let quiet = peer
+                .channel
+                .last_heard()
+                .is_none_or(|heard| now.duration_since(heard) >= IDLE_TIMEOUT);
+            if peer.outgoing.is_none() && peer.channel.is_idle() && quiet {
+                forgotten.push(*addr);
+            }
+        }
+        for addr in forgotten {
+            peers.remove(&addr);
+        }
+    }
+}
+
+async fn tcp(
+    mut listener: TcpListener,
+    kind: Kind,
+    incoming: UnboundedSender<Incoming>,
+) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let incoming = incoming.clone();
+        tokio::spawn(async move {
+            let (link, outgoing) = mpsc::unbounded_channel();
+            send(&incoming, Incoming::Opened(addr, Link(link)))?;
+            let result = match kind {
+                Kind::WebSocket => websocket(stream, addr, outgoing, &incoming).await,
+                _ => {
+                    let (reader, writer) = tokio::io::split(stream);
+                    tokio::select! {
+                        result = read_prefixed(reader, addr, &incoming) => result,
+                        result = write_prefixed(writer, outgoing) => result,
+                    }
+                }
+            };
+            if let Err(error) = result {
+                println!("Connection with {} closed: {}", addr, error);
+            }
+            send(&incoming, Incoming::Closed(addr))
+        });
+    }
+}
+
+async fn read_prefixed<R: AsyncRead + Unpin>(
+    mut reader: R,
+    addr: SocketAddr,
+    incoming: &UnboundedSender<Incoming>,
+) -> io::Result<()> {
+    loop {
+        let len = match reader.read_u32().await {
+            Ok(len) => len as usize,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        if len > MAX_MESSAGE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("a message of {} bytes is too large", len),
+            ));
+        }
+        let mut message = vec![0; len];
+        reader.read_exact(&mut message).await?;
+        send(incoming, Incoming::Message(addr, message))?;
+    }
+}
+
+//Writes the messages of the server until it lets go of the peer.
+async fn write_prefixed<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut outgoing: UnboundedReceiver<Vec<u8>>,
+) -> io::Result<()> {
+    while let Some(message) = outgoing.recv().await {
+        writer.write_u32(message.len() as u32).await?;
+        writer.write_all(&message).await?;
+    }
+    writer.shutdown().await
+}
+
+async fn websocket(
+    stream: tokio::net::TcpStream,
+    addr: SocketAddr,
+    mut outgoing: UnboundedReceiver<Vec<u8>>,
+    incoming: &UnboundedSender<Incoming>,
+) -> io::Result<()> {
+    let ws = tokio_tungstenite::accept_async(stream)
+        .await
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    let (mut sink, mut stream) = ws.split();
+    let read = async {
+        while let Some(message) = stream.next().await {
+            let message = match message {
+                Ok(Message::Text(text)) => text.into_bytes(),
+                Ok(Message::Binary(bytes)) => bytes,
+                Ok(Message::Close(_)) => break,
+                //pings are answered by tungstenite
+                Ok(_) => continue,
+                Err(error) => return Err(io::Error::other(error)),
+            };
+            if message.len() > MAX_MESSAGE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("a message of {} bytes is too large", message.len()),
+                ));
+            }
+            send(incoming, Incoming::Message(addr, message))?;
+        }
+        Ok(())
+    };
+    let write = async {
+        while let Some(message) = outgoing.recv().await {
+            let message = match String::from_utf8(message) {
+                Ok(text) => Message::Text(text),
+                Err(error) => Message::Binary(error.into_bytes()),
+            };
+            sink.send(message).await.map_err(io::Error::other)?;
+        }
+        sink.close().await.map_err(io::Error::other)
+    };
+    tokio::select! {
+        result = read => result,
+        result = write => result,
+    }
+}
+
+//Reports to the server, failing once it stopped listening.
+fn send(incoming: &UnboundedSender<Incoming>, event: Incoming) -> io::Result<()> {
+    incoming
+        .send(event)
+        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the server stopped"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:6142".parse().unwrap()
+    }
+
+    //`tokio::test` can't be used as the `core` crate of the game hides the standard one
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Runtime::new().unwrap().block_on(future)
+    }
+
+    fn message(event: Option<Incoming>) -> Vec<u8> {
+        match event {
+            Some(Incoming::Message(addr, message)) if addr == peer() => message,
+            _ => panic!("expected a message"),
+        }
+    }
+
+    #[test]
+    fn test_prefixed() {
+        block_on(prefixed());
+    }
+
+    async fn prefixed() {
+        let (link, outgoing) = mpsc::unbounded_channel();
+        link.send(b"hello".to_vec()).unwrap();
+        link.send(Vec::new()).unwrap();
+        drop(link);
+        let mut written = Vec::new();
+        write_prefixed(&mut written, outgoing).await.unwrap();
+        assert_eq!(written, b"\0\0\0\x05hello\0\0\0\0");
+
+        let (sender, mut incoming) = mpsc::unbounded_channel();
+        read_prefixed(written.as_slice(), peer(), &sender)
+            .await
+            .unwrap();
+        assert_eq!(message(incoming.recv().await), b"hello");
+        assert_eq!(message(incoming.recv().await), b"");
+
+        //a message cut short is an error, unlike a connection closed between two messages
+        let error = read_prefixed(&written[..7], peer(), &sender)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        let len = (MAX_MESSAGE as u32 + 1).to_be_bytes();
+        let error = read_prefixed(&len[..], peer(), &sender).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(incoming.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_websocket() {
+        block_on(websocket_frames());
+    }
+
+    async fn websocket_frames() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let (sender, mut incoming) = mpsc::unbounded_channel();
+        let (link, outgoing) = mpsc::unbounded_channel();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            websocket(stream, peer(), outgoing, &sender).await
+        });
+        let stream = TcpStream::connect(local).await.unwrap();
+        let (mut client, _) = tokio_tungstenite::client_async(format!("ws://{}", local), stream)
+            .await
+            .unwrap();
+
+        //messages which are valid UTF-8 are sent as text, others as binary
+        link.send(b"{}".to_vec()).unwrap();
+        link.send(vec![0xff, 0]).unwrap();
+        let text = client.next().await.unwrap().unwrap();
+        assert_eq!(text, Message::Text("{}".to_string()));
+        let binary = client.next().await.unwrap().unwrap();
+        assert_eq!(binary, Message::Binary(vec![0xff, 0]));
+
+        //both are read as bytes
+        client.send(Message::Text("{}".to_string())).await.unwrap();
+        client.send(Message::Binary(vec![0xff, 0])).await.unwrap();
+        assert_eq!(message(incoming.recv().await), b"{}");
+        assert_eq!(message(incoming.recv().await), vec![0xff, 0]);
+
+        let large = Message::Binary(vec![0; MAX_MESSAGE + 1]);
+        client.send(large).await.unwrap();
+        let error = server.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}